@@ -7,4 +7,5 @@ apt install -y libwebkit2gtk-4.0-dev libssl-dev
 curl https://sh.rustup.rs -sSf | sh -s -- -y
 export PATH=~/.cargo/bin:$PATH
 
-cargo build
+# The test config isn't signed
+LIFTINSTALL_BOOTSTRAP=bootstrap.linux.dev.toml cargo build
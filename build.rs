@@ -14,10 +14,12 @@ extern crate toml;
 use walkdir::WalkDir;
 
 use std::env;
+use std::path::Path;
 use std::path::PathBuf;
 
 use std::fs::copy;
 use std::fs::create_dir_all;
+use std::fs::read_dir;
 use std::fs::File;
 
 use std::io::BufRead;
@@ -34,6 +36,65 @@ const FILES_TO_PREPROCESS: &'static [&'static str] = &["helpers.js", "views.js"]
 pub struct BaseAttributes {
     pub name: String,
     pub target_url: String,
+    /// A directory of package archives to bundle into the installer
+    #[serde(default)]
+    pub embedded_packages: Option<String>,
+    /// A minisign public key used to verify the config and packages
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub allow_unsigned: bool,
+}
+
+/// Copies package archives into the build directory, and generates a table of them
+/// which is included by the "embedded" release source.
+fn embed_packages(config: &BaseAttributes, output_dir: &Path) {
+    let target_dir = output_dir.join("embedded");
+    create_dir_all(&target_dir).expect("Unable to create dir");
+
+    let mut table = String::from("&[\n");
+
+    if let Some(ref source_dir) = config.embedded_packages {
+        // Picks up packages being added or removed
+        println!("cargo:rerun-if-changed={}", source_dir);
+
+        let entries = read_dir(source_dir).expect("Unable to read embedded packages directory");
+
+        for entry in entries {
+            let entry = entry.expect("Unable to read embedded packages directory");
+
+            if !entry.path().is_file() {
+                continue;
+            }
+
+            let filename = entry
+                .file_name()
+                .into_string()
+                .expect("Unable to convert to string");
+
+            println!("cargo:rerun-if-changed={}", entry.path().display());
+
+            let output_file = target_dir.join(&filename);
+            copy(entry.path(), &output_file).expect("Unable to copy file");
+
+            let output_file = output_file
+                .to_str()
+                .expect("Unable to convert to string")
+                .to_string();
+
+            table += &format!(
+                "    ({:?}, &include_bytes!({:?})[..]),\n",
+                filename, output_file
+            );
+        }
+    }
+
+    table += "]\n";
+
+    let mut target = File::create(output_dir.join("embedded.rs")).expect("Unable to create file");
+    target
+        .write_all(table.as_bytes())
+        .expect("Unable to write embedded package table");
 }
 
 #[cfg(windows)]
@@ -51,6 +112,9 @@ fn handle_binary(config: &BaseAttributes) {
     );
     res.compile().expect("Failed to generate metadata");
 
+    println!("cargo:rerun-if-changed=static/favicon.ico");
+    println!("cargo:rerun-if-changed=src/native/interop.cpp");
+
     cc::Build::new()
         .cpp(true)
         .file("src/native/interop.cpp")
@@ -65,8 +129,16 @@ fn main() {
 
     let os = OS.to_lowercase();
 
-    // Find target config
-    let target_config = PathBuf::from(format!("bootstrap.{}.toml", os));
+    // Find target config, which may be swapped for another, such as one for development
+    let target_config = match env::var("LIFTINSTALL_BOOTSTRAP") {
+        Ok(v) => PathBuf::from(v),
+        Err(_) => PathBuf::from(format!("bootstrap.{}.toml", os)),
+    };
+
+    // Once any of these are printed, Cargo only reruns this script for the listed paths
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", target_config.display());
+    println!("cargo:rerun-if-env-changed=LIFTINSTALL_BOOTSTRAP");
 
     if !target_config.exists() {
         panic!(
@@ -87,7 +159,17 @@ fn main() {
 
     let config: BaseAttributes =
         toml::from_slice(&file_contents).expect("Unable to parse config file");
+
+    if config.public_key.is_none() && !config.allow_unsigned {
+        panic!(
+            "{:?} has no public key to verify downloads with. Add a \"public_key\", or \
+             for development, build with LIFTINSTALL_BOOTSTRAP=bootstrap.{}.dev.toml \
+             to explicitly accept unsigned content.",
+            target_config, os
+        );
+    }
     handle_binary(&config);
+    embed_packages(&config, &output_dir);
 
     // Copy for the main build
     copy(&target_config, output_dir.join("bootstrap.toml")).expect("Unable to copy config file");
@@ -96,6 +178,8 @@ fn main() {
     for entry in WalkDir::new("static") {
         let entry = entry.expect("Unable to read output directory");
 
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+
         let output_file = output_dir.join(entry.path());
 
         if entry.path().is_dir() {
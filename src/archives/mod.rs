@@ -5,29 +5,76 @@ use zip::ZipArchive as UpstreamZipArchive;
 use tar::Archive as UpstreamTarArchive;
 use tar::EntryType;
 
-use std::io::Cursor;
+use std::fs::read_dir;
+use std::fs::read_link;
+use std::fs::symlink_metadata;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::empty;
+use std::io::BufReader;
 use std::io::Read;
+use std::io::Write;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::iter::Iterator;
+use std::path::Component;
+use std::path::Path;
 use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
 
-use xz_decom;
+use config::ExtractionLimits;
+use config::PackageDescription;
+
+use glob::MatchOptions;
+use glob::Pattern;
+
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// The type of an individual entry within an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic link, pointing to the contained path
+    Symlink(PathBuf),
+    /// A hard link to an earlier entry at the contained path in the archive
+    HardLink(PathBuf),
+}
+
+/// Metadata about an individual entry within an archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// Unix permission bits, if the archive recorded them
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the UNIX epoch, if the archive recorded it
+    pub mtime: Option<u64>,
+}
 
 pub trait Archive<'a> {
-    /// func: iterator value, max size, file name, file contents
+    /// func: iterator value, max size, entry metadata, file contents
     fn for_each(
         &mut self,
-        func: &mut FnMut(usize, Option<usize>, PathBuf, &mut Read) -> Result<(), String>,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
     ) -> Result<(), String>;
 }
 
-struct ZipArchive<'a> {
-    archive: UpstreamZipArchive<Cursor<&'a [u8]>>,
+/// The file type bits of a Unix mode, and the value of these for a symlink.
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+struct ZipArchive {
+    archive: UpstreamZipArchive<File>,
 }
 
-impl<'a> Archive<'a> for ZipArchive<'a> {
+impl<'a> Archive<'a> for ZipArchive {
     fn for_each(
         &mut self,
-        func: &mut FnMut(usize, Option<usize>, PathBuf, &mut Read) -> Result<(), String>,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
     ) -> Result<(), String> {
         let max = self.archive.len();
 
@@ -37,11 +84,31 @@ impl<'a> Archive<'a> for ZipArchive<'a> {
                 .by_index(i)
                 .map_err(|v| format!("Error while reading from .zip file: {:?}", v))?;
 
+            let mode = archive.unix_mode();
+            let mtime = archive.last_modified().to_timespec().sec;
+
+            let mut entry = ArchiveEntry {
+                path: PathBuf::from(archive.name().replace('\\', "/")),
+                kind: EntryKind::File,
+                mode: mode.map(|x| x & 0o7777),
+                mtime: if mtime > 0 { Some(mtime as u64) } else { None },
+            };
+
             if archive.name().ends_with('/') || archive.name().ends_with('\\') {
-                continue;
-            }
+                entry.kind = EntryKind::Directory;
+                func(i, Some(max), entry, &mut empty())?;
+            } else if mode.map(|x| x & S_IFMT == S_IFLNK).unwrap_or(false) {
+                // Symlinks store their target as the contents of the entry
+                let mut target = String::new();
+                archive
+                    .read_to_string(&mut target)
+                    .map_err(|v| format!("Error while reading from .zip file: {:?}", v))?;
 
-            func(i, Some(max), archive.sanitized_name(), &mut archive)?;
+                entry.kind = EntryKind::Symlink(PathBuf::from(target));
+                func(i, Some(max), entry, &mut empty())?;
+            } else {
+                func(i, Some(max), entry, &mut archive)?;
+            }
         }
 
         Ok(())
@@ -55,7 +122,7 @@ struct TarArchive<'a> {
 impl<'a> Archive<'a> for TarArchive<'a> {
     fn for_each(
         &mut self,
-        func: &mut FnMut(usize, Option<usize>, PathBuf, &mut Read) -> Result<(), String>,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
     ) -> Result<(), String> {
         let entries = self
             .archive
@@ -66,41 +133,886 @@ impl<'a> Archive<'a> for TarArchive<'a> {
             let mut entry =
                 entry.map_err(|v| format!("Failed to read entry from .tar file: {:?}", v))?;
 
-            if entry.header().entry_type() != EntryType::Regular {
-                continue;
-            }
-
             let path = entry
                 .path()
                 .map(PathBuf::from)
                 .map_err(|v| format!("Failed to read entry from .tar file: {:?}", v))?;
 
-            func(i, None, path, &mut entry)?;
+            let kind = match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => EntryKind::File,
+                EntryType::Directory => EntryKind::Directory,
+                EntryType::Symlink => {
+                    let target = entry
+                        .link_name()
+                        .map_err(|v| format!("Failed to read entry from .tar file: {:?}", v))?
+                        .ok_or_else(|| format!("Symlink {:?} has no target", path))?;
+
+                    EntryKind::Symlink(target.into_owned())
+                }
+                EntryType::Link => {
+                    let target = entry
+                        .link_name()
+                        .map_err(|v| format!("Failed to read entry from .tar file: {:?}", v))?
+                        .ok_or_else(|| format!("Hard link {:?} has no target", path))?;
+
+                    EntryKind::HardLink(target.into_owned())
+                }
+                other => {
+                    warn!("Skipping {:?}, as {:?} entries are unsupported", path, other);
+                    continue;
+                }
+            };
+
+            let archive_entry = ArchiveEntry {
+                path,
+                kind,
+                mode: entry.header().mode().ok().map(|x| x & 0o7777),
+                mtime: entry.header().mtime().ok(),
+            };
+
+            func(i, None, archive_entry, &mut entry)?;
         }
 
         Ok(())
     }
 }
 
-/// Reads the named archive with an archive implementation.
-pub fn read_archive<'a>(name: &str, data: &'a [u8]) -> Result<Box<Archive<'a> + 'a>, String> {
-    if name.ends_with(".zip") {
-        // Decompress a .zip file
-        let archive = UpstreamZipArchive::new(Cursor::new(data))
-            .map_err(|x| format!("Error while reading .zip file: {:?}", x))?;
+/// A directory of already extracted files, such as the result of applying a delta.
+struct DirectoryArchive {
+    root: PathBuf,
+    files: Vec<PathBuf>,
+}
+
+impl DirectoryArchive {
+    fn new(root: &Path) -> Result<DirectoryArchive, String> {
+        let mut files = Vec::new();
+        let mut pending = vec![root.to_path_buf()];
+
+        while let Some(dir) = pending.pop() {
+            let entries =
+                read_dir(&dir).map_err(|x| format!("Failed to read dir {:?}: {:?}", dir, x))?;
+
+            for entry in entries {
+                let path = entry
+                    .map_err(|x| format!("Failed to read dir {:?}: {:?}", dir, x))?
+                    .path();
+
+                if let Ok(relative) = path.strip_prefix(root) {
+                    files.push(relative.to_path_buf());
+                }
+
+                if path.is_dir() && !is_symlink(&path) {
+                    pending.push(path);
+                }
+            }
+        }
+
+        // Sorting ensures that directories come before their contents
+        files.sort();
+
+        Ok(DirectoryArchive {
+            root: root.to_path_buf(),
+            files,
+        })
+    }
+}
+
+/// Returns true if the path is a symbolic link, rather than what it points to.
+fn is_symlink(path: &Path) -> bool {
+    symlink_metadata(path)
+        .map(|x| x.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Returns the permission bits of a file, on platforms which have them.
+#[cfg(unix)]
+fn unix_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+impl<'a> Archive<'a> for DirectoryArchive {
+    fn for_each(
+        &mut self,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let max = self.files.len();
+
+        for (i, relative) in self.files.iter().enumerate() {
+            let path = self.root.join(relative);
+
+            let metadata = symlink_metadata(&path)
+                .map_err(|x| format!("Failed to read {:?}: {:?}", path, x))?;
+
+            let mut entry = ArchiveEntry {
+                path: relative.clone(),
+                kind: EntryKind::File,
+                mode: unix_mode(&metadata),
+                mtime: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                    .map(|x| x.as_secs()),
+            };
+
+            if metadata.file_type().is_symlink() {
+                let target =
+                    read_link(&path).map_err(|x| format!("Failed to read {:?}: {:?}", path, x))?;
+
+                entry.kind = EntryKind::Symlink(target);
+                func(i, Some(max), entry, &mut empty())?;
+            } else if metadata.is_dir() {
+                entry.kind = EntryKind::Directory;
+                func(i, Some(max), entry, &mut empty())?;
+            } else {
+                let mut file =
+                    File::open(&path).map_err(|x| format!("Failed to open {:?}: {:?}", path, x))?;
+
+                func(i, Some(max), entry, &mut file)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A single file which is installed as-is.
+struct RawArchive {
+    file: File,
+    entry: ArchiveEntry,
+}
+
+impl<'a> Archive<'a> for RawArchive {
+    fn for_each(
+        &mut self,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
+    ) -> Result<(), String> {
+        func(0, Some(1), self.entry.clone(), &mut self.file)
+    }
+}
+
+/// Ensures that a path from an archive is relative, and stays within the directory it
+/// is extracted to.
+pub fn check_path(path: &Path) -> Result<PathBuf, String> {
+    let mut result = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(v) => result.push(v),
+            Component::CurDir => {}
+            _ => return Err(format!("Archive contains an unsafe path: {:?}", path)),
+        }
+    }
+
+    if result.as_os_str().is_empty() {
+        return Err(format!("Archive contains an empty path: {:?}", path));
+    }
+
+    Ok(result)
+}
+
+/// Ensures that a symlink at the specified (already checked) path doesn't point outside
+/// of the directory it is extracted to.
+fn check_symlink(path: &Path, target: &Path) -> Result<(), String> {
+    // Relative targets are resolved from the directory containing the link
+    let mut depth = path.components().count() as isize - 1;
+    let mut descended = false;
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => {
+                depth += 1;
+                descended = true;
+            }
+            Component::CurDir => {}
+            // Going back up after descending could pass through another link
+            Component::ParentDir if depth > 0 && !descended => depth -= 1,
+            _ => {
+                return Err(format!(
+                    "Archive contains a symlink escaping the install directory: {:?} -> {:?}",
+                    path, target
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensures that a path about to be written to is within the install directory, after
+/// following any links created so far. Symlink targets are checked from where the link
+/// will actually be created.
+pub fn check_within(root: &Path, target: &Path, link: Option<&Path>) -> Result<(), String> {
+    let root = root
+        .canonicalize()
+        .map_err(|x| format!("Unable to resolve {:?}: {:?}", root, x))?;
+
+    // Only existing directories can be links, so resolve the deepest one of these
+    let mut existing = target
+        .parent()
+        .ok_or_else(|| format!("{:?} has no parent directory", target))?;
+    let mut remaining = Vec::new();
+
+    while !existing.exists() {
+        remaining.push(existing.file_name().map(|x| x.to_owned()));
+        existing = existing
+            .parent()
+            .ok_or_else(|| format!("{:?} has no parent directory", target))?;
+    }
+
+    let mut resolved = existing
+        .canonicalize()
+        .map_err(|x| format!("Unable to resolve {:?}: {:?}", existing, x))?;
+
+    for component in remaining.into_iter().rev() {
+        match component {
+            Some(v) => resolved.push(v),
+            None => return Err(format!("Archive contains an unsafe path: {:?}", target)),
+        }
+    }
+
+    if !resolved.starts_with(&root) {
+        return Err(format!("{:?} is outside of the install directory", target));
+    }
+
+    if let Some(link) = link {
+        for component in link.components() {
+            match component {
+                Component::Normal(v) => resolved.push(v),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                _ => resolved = PathBuf::from("/"),
+            }
+
+            if !resolved.starts_with(&root) {
+                return Err(format!(
+                    "Symlink {:?} points outside of the install directory: {:?}",
+                    target, link
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates every entry of another archive before it is handed out.
+struct CheckedArchive<'a> {
+    inner: Box<Archive<'a> + 'a>,
+}
+
+impl<'a> Archive<'a> for CheckedArchive<'a> {
+    fn for_each(
+        &mut self,
+        func: &mut FnMut(usize, Option<usize>, ArchiveEntry, &mut Read) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.inner.for_each(&mut |i, max, mut entry, reader| {
+            entry.path = check_path(&entry.path)?;
+
+            entry.kind = match entry.kind {
+                EntryKind::Symlink(target) => {
+                    check_symlink(&entry.path, &target)?;
+                    EntryKind::Symlink(target)
+                }
+                EntryKind::HardLink(target) => EntryKind::HardLink(check_path(&target)?),
+                other => other,
+            };
+
+            func(i, max, entry, reader)
+        })
+    }
+}
+
+/// How glob patterns are matched against paths. `*` stays within a directory, while
+/// `**` matches any number of them.
+const PATH_MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Selects which entries of an archive are extracted, and where to.
+pub struct EntryFilter {
+    strip_components: usize,
+    subdirectory: Option<PathBuf>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+/// Compiles a list of glob patterns.
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>, String> {
+    patterns
+        .iter()
+        .map(|x| Pattern::new(x).map_err(|v| format!("Invalid pattern {:?}: {:?}", x, v)))
+        .collect()
+}
+
+/// Checks if a path (with forward slashes) matches any of a list of patterns.
+pub fn matches_any(patterns: &[Pattern], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|x| x.matches_with(name, &PATH_MATCH_OPTIONS))
+}
+
+impl EntryFilter {
+    /// Builds a filter from the extraction options of a package.
+    pub fn new(package: &PackageDescription) -> Result<Self, String> {
+        let subdirectory = match package.subdirectory {
+            Some(ref v) => Some(check_path(Path::new(v))?),
+            None => None,
+        };
+
+        Ok(EntryFilter {
+            strip_components: package.strip_components,
+            subdirectory,
+            include: compile_patterns(&package.include)?,
+            exclude: compile_patterns(&package.exclude)?,
+        })
+    }
+
+    /// Maps a path in the archive to where it should be extracted, or returns None if
+    /// it should be skipped.
+    pub fn apply(&self, path: &Path) -> Option<PathBuf> {
+        let mut path: PathBuf = path.components().skip(self.strip_components).collect();
+
+        if let Some(ref subdirectory) = self.subdirectory {
+            path = path.strip_prefix(subdirectory).ok()?.to_path_buf();
+        }
+
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+
+        // Patterns always use forward slashes, regardless of platform
+        let name = path
+            .components()
+            .map(|x| x.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if !self.include.is_empty() && !matches_any(&self.include, &name) {
+            return None;
+        }
+
+        if matches_any(&self.exclude, &name) {
+            return None;
+        }
+
+        Some(path)
+    }
+}
+
+/// Tracks how much has been extracted from an archive, failing once any of the
+/// configured limits are exceeded.
+pub struct ExtractionGuard {
+    limits: ExtractionLimits,
+    /// Size of the archive on disk, if it is compressed
+    archive_size: Option<u64>,
+    entries: usize,
+    written: u64,
+}
+
+impl ExtractionGuard {
+    /// Starts tracking an extraction, given the size of the archive being read.
+    pub fn new(limits: ExtractionLimits, archive_size: Option<u64>) -> Self {
+        ExtractionGuard {
+            limits,
+            archive_size,
+            entries: 0,
+            written: 0,
+        }
+    }
+
+    /// Counts a new entry in the archive.
+    pub fn add_entry(&mut self) -> Result<(), String> {
+        self.entries += 1;
+
+        if self.entries > self.limits.max_entries {
+            return Err(format!(
+                "Package contains more than {} entries",
+                self.limits.max_entries
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a number of additional bytes can be extracted.
+    fn check_size(&self, len: u64) -> Result<(), String> {
+        let total = self.written + len;
+
+        if total > self.limits.max_size * 1024 * 1024 {
+            return Err(format!(
+                "Package extracts to more than {} MB",
+                self.limits.max_size
+            ));
+        }
+
+        if let Some(archive_size) = self.archive_size {
+            if total > archive_size.max(1).saturating_mul(self.limits.max_ratio) {
+                return Err(format!(
+                    "Package has a compression ratio greater than {}:1",
+                    self.limits.max_ratio
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts bytes which were extracted without going through `copy`.
+    pub fn add_bytes(&mut self, len: u64) -> Result<(), String> {
+        self.check_size(len)?;
+        self.written += len;
+
+        Ok(())
+    }
+
+    /// How many more bytes can be extracted before the size limit is reached.
+    pub fn remaining(&self) -> u64 {
+        (self.limits.max_size * 1024 * 1024).saturating_sub(self.written)
+    }
+
+    /// Copies an entry's contents, checking the limits before each block is written.
+    pub fn copy(&mut self, reader: &mut Read, writer: &mut Write) -> Result<u64, String> {
+        let mut buf = [0u8; 8192];
+        let mut copied = 0;
+
+        loop {
+            let len = reader
+                .read(&mut buf)
+                .map_err(|x| format!("Unable to read from archive: {:?}", x))?;
+
+            if len == 0 {
+                break;
+            }
+
+            self.check_size(len as u64)?;
+
+            writer
+                .write_all(&buf[0..len])
+                .map_err(|x| format!("Unable to write to file: {:?}", x))?;
+
+            self.written += len as u64;
+            copied += len as u64;
+        }
+
+        Ok(copied)
+    }
+}
+
+/// The container or compression format of an archive.
+#[derive(Debug, PartialEq)]
+enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarBz2,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Identifies a format from the first bytes of a file, falling back to the file name
+    /// for formats which don't have a reliable signature.
+    fn detect(name: &str, header: &[u8]) -> Option<ArchiveFormat> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if header.starts_with(&[0x1F, 0x8B]) {
+            Some(ArchiveFormat::TarGz)
+        } else if header.starts_with(b"BZh") {
+            Some(ArchiveFormat::TarBz2)
+        } else if header.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if header.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Some(ArchiveFormat::TarZst)
+        } else if header.len() >= 262 && &header[257..262] == b"ustar" {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar") {
+            // Pre-POSIX tar files have no magic number
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads as much of the start of a file as is available, up to the size of the buffer.
+fn read_header(file: &mut File, buf: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let len = file
+            .read(&mut buf[filled..])
+            .map_err(|x| format!("Failed to read archive header: {:?}", x))?;
+
+        if len == 0 {
+            break;
+        }
+
+        filled += len;
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|x| format!("Failed to seek archive: {:?}", x))?;
+
+    Ok(filled)
+}
+
+/// Reads the named archive from disk with an archive implementation.
+pub fn read_archive<'a>(name: &str, path: &Path) -> Result<Box<Archive<'a> + 'a>, String> {
+    let inner = open_archive(name, path)?;
+
+    Ok(Box::new(CheckedArchive { inner }))
+}
+
+/// Reads a single downloaded file, which is installed at the specified relative path.
+pub fn read_raw_file<'a>(
+    path: &Path,
+    target: &Path,
+    executable: bool,
+) -> Result<Box<Archive<'a> + 'a>, String> {
+    let file = File::open(path).map_err(|x| format!("Failed to open {:?}: {:?}", path, x))?;
+
+    let entry = ArchiveEntry {
+        path: target.to_path_buf(),
+        kind: EntryKind::File,
+        mode: Some(if executable { 0o755 } else { 0o644 }),
+        mtime: None,
+    };
+
+    Ok(Box::new(CheckedArchive {
+        inner: Box::new(RawArchive { file, entry }),
+    }))
+}
+
+fn open_archive<'a>(name: &str, path: &Path) -> Result<Box<Archive<'a> + 'a>, String> {
+    if path.is_dir() {
+        return Ok(Box::new(DirectoryArchive::new(path)?));
+    }
+
+    let mut file =
+        File::open(path).map_err(|x| format!("Failed to open archive {:?}: {:?}", path, x))?;
+
+    // Large enough to contain the "ustar" magic of a tar header
+    let mut header = [0u8; 512];
+    let header_len = read_header(&mut file, &mut header)?;
+
+    let format = ArchiveFormat::detect(name, &header[0..header_len])
+        .ok_or_else(|| format!("No decompression handler for {:?}.", name))?;
+
+    info!("Reading {:?} as {:?}", name, format);
+
+    let contents: Box<Read> = match format {
+        ArchiveFormat::Zip => {
+            // Decompress a .zip file
+            let archive = UpstreamZipArchive::new(file)
+                .map_err(|x| format!("Error while reading .zip file: {:?}", x))?;
+
+            return Ok(Box::new(ZipArchive { archive }));
+        }
+        ArchiveFormat::Tar => Box::new(BufReader::new(file)),
+        ArchiveFormat::TarGz => Box::new(GzDecoder::new(BufReader::new(file))),
+        ArchiveFormat::TarBz2 => Box::new(BzDecoder::new(BufReader::new(file))),
+        ArchiveFormat::TarZst => Box::new(
+            ZstdDecoder::new(file)
+                .map_err(|x| format!("Failed to build decompressor: {:?}", x))?,
+        ),
+        ArchiveFormat::TarXz => Box::new(XzDecoder::new(BufReader::new(file))),
+    };
+
+    let tar = UpstreamTarArchive::new(contents);
+
+    Ok(Box::new(TarArchive { archive: tar }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    /// Creates an empty directory for a test to work in.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("archives-test-{}-{}", name, ::std::process::id()));
+        if path.exists() {
+            remove_dir_all(&path).unwrap();
+        }
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn check_path_normalises_relative_paths() {
+        assert_eq!(check_path(Path::new("a/b")), Ok(PathBuf::from("a/b")));
+        assert_eq!(check_path(Path::new("./a/./b")), Ok(PathBuf::from("a/b")));
+    }
+
+    #[test]
+    fn check_path_rejects_traversal() {
+        assert!(check_path(Path::new("../a")).is_err());
+        assert!(check_path(Path::new("a/../../b")).is_err());
+        // Even when it would stay within the directory
+        assert!(check_path(Path::new("a/../b")).is_err());
+    }
+
+    #[test]
+    fn check_path_rejects_absolute_and_empty_paths() {
+        assert!(check_path(Path::new("/etc/passwd")).is_err());
+        assert!(check_path(Path::new("")).is_err());
+        assert!(check_path(Path::new("./")).is_err());
+    }
+
+    #[test]
+    fn check_symlink_allows_targets_within_the_directory() {
+        assert!(check_symlink(Path::new("a"), Path::new("b")).is_ok());
+        assert!(check_symlink(Path::new("a/b"), Path::new("../c")).is_ok());
+        assert!(check_symlink(Path::new("a/b/c"), Path::new("../../d/e")).is_ok());
+        assert!(check_symlink(Path::new("a/b"), Path::new("./c")).is_ok());
+    }
+
+    #[test]
+    fn check_symlink_rejects_escaping_targets() {
+        assert!(check_symlink(Path::new("a"), Path::new("../b")).is_err());
+        assert!(check_symlink(Path::new("a/b"), Path::new("../../c")).is_err());
+        assert!(check_symlink(Path::new("a"), Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn check_symlink_rejects_going_up_after_descending() {
+        // "b" could itself be a link, so ".." doesn't necessarily lead back to "a"
+        assert!(check_symlink(Path::new("a/l"), Path::new("b/../c")).is_err());
+    }
+
+    #[test]
+    fn check_within_allows_new_paths_inside_root() {
+        let root = scratch_dir("within-inside");
+
+        assert!(check_within(&root, &root.join("a"), None).is_ok());
+        assert!(check_within(&root, &root.join("a/b/c"), None).is_ok());
+        assert!(check_within(&root, &root.join("a/b"), Some(Path::new("../c"))).is_ok());
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn check_within_rejects_links_pointing_outside() {
+        let root = scratch_dir("within-link");
+
+        assert!(check_within(&root, &root.join("a"), Some(Path::new("../b"))).is_err());
+        assert!(check_within(&root, &root.join("a/b"), Some(Path::new("../../c"))).is_err());
+        assert!(check_within(&root, &root.join("a"), Some(Path::new("/etc"))).is_err());
+
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_within_follows_links_created_earlier() {
+        use std::os::unix::fs::symlink;
+
+        let root = scratch_dir("within-follow");
+        let outside = scratch_dir("within-follow-outside");
+
+        // A link which passed its own check, but now leads elsewhere
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink(".", root.join("self")).unwrap();
+
+        assert!(check_within(&root, &root.join("escape/file"), None).is_err());
+        assert!(check_within(&root, &root.join("escape/new/file"), None).is_err());
+        assert!(check_within(&root, &root.join("self/file"), None).is_ok());
+
+        remove_dir_all(&root).unwrap();
+        remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn detect_uses_magic_bytes() {
+        assert_eq!(
+            ArchiveFormat::detect("a", b"PK\x03\x04"),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a", b"PK\x05\x06"),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a", &[0x1F, 0x8B, 0x08]),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a", b"BZh91AY"),
+            Some(ArchiveFormat::TarBz2)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a", &[0xFD, b'7', b'z', b'X', b'Z', 0x00]),
+            Some(ArchiveFormat::TarXz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a", &[0x28, 0xB5, 0x2F, 0xFD]),
+            Some(ArchiveFormat::TarZst)
+        );
+
+        let mut tar = [0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(ArchiveFormat::detect("a", &tar), Some(ArchiveFormat::Tar));
+    }
+
+    #[test]
+    fn detect_prefers_magic_bytes_over_names() {
+        assert_eq!(
+            ArchiveFormat::detect("a.zip", &[0x1F, 0x8B]),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a.tar", b"PK\x03\x04"),
+            Some(ArchiveFormat::Zip)
+        );
+    }
+
+    #[test]
+    fn detect_falls_back_to_names_for_old_tar_files() {
+        assert_eq!(
+            ArchiveFormat::detect("a.tar", &[0u8; 512]),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(
+            ArchiveFormat::detect("a.tar", b""),
+            Some(ArchiveFormat::Tar)
+        );
+        assert_eq!(ArchiveFormat::detect("a.tar.gz", &[0u8; 512]), None);
+        assert_eq!(ArchiveFormat::detect("a.bin", b"PK"), None);
+        // Too short to contain a tar header
+        assert_eq!(ArchiveFormat::detect("a", &[0u8; 261]), None);
+    }
+
+    /// Builds a filter from the extraction options of a package's config.
+    fn build_filter(options: &str) -> Result<EntryFilter, String> {
+        let package: PackageDescription = ::toml::from_str(&format!(
+            "name = \"test\"\n\
+             description = \"\"\n\
+             {}\n\
+             [source]\n\
+             name = \"github\"\n\
+             match = \".*\"\n\
+             config = {{ repo = \"a/b\" }}\n",
+            options
+        ))
+        .unwrap();
+
+        EntryFilter::new(&package)
+    }
+
+    fn apply(filter: &EntryFilter, path: &str) -> Option<PathBuf> {
+        filter.apply(Path::new(path))
+    }
+
+    #[test]
+    fn filter_strips_leading_components() {
+        let filter = build_filter("strip_components = 1").unwrap();
+
+        assert_eq!(
+            apply(&filter, "project-1.2.3/bin/a"),
+            Some(PathBuf::from("bin/a"))
+        );
+        // The stripped directories themselves are skipped
+        assert_eq!(apply(&filter, "project-1.2.3"), None);
+
+        let filter = build_filter("strip_components = 3").unwrap();
+        assert_eq!(apply(&filter, "a/b"), None);
+    }
+
+    #[test]
+    fn filter_selects_subdirectory() {
+        let filter = build_filter("strip_components = 1\nsubdirectory = \"dist\"").unwrap();
+
+        assert_eq!(apply(&filter, "p/dist/bin/a"), Some(PathBuf::from("bin/a")));
+        assert_eq!(apply(&filter, "p/dist"), None);
+        assert_eq!(apply(&filter, "p/src/a"), None);
+        // Only whole directory names match
+        assert_eq!(apply(&filter, "p/distribution/a"), None);
+    }
+
+    #[test]
+    fn filter_rejects_unsafe_subdirectory() {
+        assert!(build_filter("subdirectory = \"../a\"").is_err());
+        assert!(build_filter("subdirectory = \"/a\"").is_err());
+    }
+
+    #[test]
+    fn filter_rejects_invalid_patterns() {
+        assert!(build_filter("include = [\"a/[\"]").is_err());
+    }
+
+    #[test]
+    fn filter_matches_patterns_after_stripping() {
+        let filter = build_filter(
+            "strip_components = 1\n\
+             include = [\"bin/*\", \"share/**/*.txt\"]\n\
+             exclude = [\"bin/*.pdb\"]",
+        )
+        .unwrap();
+
+        assert_eq!(apply(&filter, "p/bin/a"), Some(PathBuf::from("bin/a")));
+        assert_eq!(apply(&filter, "p/bin/a.pdb"), None);
+        assert_eq!(
+            apply(&filter, "p/share/doc/a.txt"),
+            Some(PathBuf::from("share/doc/a.txt"))
+        );
+        assert_eq!(
+            apply(&filter, "p/share/a.txt"),
+            Some(PathBuf::from("share/a.txt"))
+        );
+        assert_eq!(apply(&filter, "p/lib/a"), None);
+    }
+
+    #[test]
+    fn filter_patterns_only_cross_directories_with_double_star() {
+        let filter = build_filter("include = [\"*.txt\", \"bin/*\"]").unwrap();
+
+        assert_eq!(apply(&filter, "a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(apply(&filter, "docs/a.txt"), None);
+        assert_eq!(apply(&filter, "bin/a"), Some(PathBuf::from("bin/a")));
+        assert_eq!(apply(&filter, "bin/sub/a"), None);
+
+        let filter = build_filter("include = [\"**/*.txt\"]").unwrap();
+        assert_eq!(
+            apply(&filter, "docs/a.txt"),
+            Some(PathBuf::from("docs/a.txt"))
+        );
+        assert_eq!(apply(&filter, "a.txt"), Some(PathBuf::from("a.txt")));
+    }
 
-        Ok(Box::new(ZipArchive { archive }))
-    } else if name.ends_with(".tar.xz") {
-        // Decompress a .tar.xz file
-        let decompressed_data = xz_decom::decompress(data)
-            .map_err(|x| format!("Failed to build decompressor: {:?}", x))?;
+    #[test]
+    fn extraction_guard_enforces_size_and_ratio() {
+        let limits = ExtractionLimits {
+            max_size: 1,
+            max_entries: 2,
+            max_ratio: 10,
+        };
 
-        let decompressed_contents: Box<Read> = Box::new(Cursor::new(decompressed_data));
+        let mut guard = ExtractionGuard::new(limits.clone(), Some(100));
+        let mut output = Vec::new();
+        assert_eq!(guard.copy(&mut &[0u8; 1000][..], &mut output), Ok(1000));
+        assert!(guard.copy(&mut &[0u8; 1][..], &mut output).is_err());
 
-        let tar = UpstreamTarArchive::new(decompressed_contents);
+        let mut guard = ExtractionGuard::new(limits.clone(), None);
+        assert!(guard.add_bytes(1024 * 1024).is_ok());
+        assert_eq!(guard.remaining(), 0);
+        assert!(guard.add_bytes(1).is_err());
 
-        Ok(Box::new(TarArchive { archive: tar }))
-    } else {
-        Err(format!("No decompression handler for {:?}.", name))
+        let mut guard = ExtractionGuard::new(limits, None);
+        assert!(guard.add_entry().is_ok());
+        assert!(guard.add_entry().is_ok());
+        assert!(guard.add_entry().is_err());
     }
 }
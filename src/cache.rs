@@ -0,0 +1,364 @@
+//! cache.rs
+//!
+//! Contains a persistent cache of downloaded packages, shared between installs of the
+//! same application so that reinstalls and repairs don't need to download them again.
+
+use serde_json;
+
+use std::cmp::Reverse;
+use std::fs::{copy, create_dir_all, remove_dir_all, remove_file, File};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use checksum::sha256_reader;
+
+use logging::LoggingErrors;
+
+lazy_static! {
+    /// Serialises access to cache indexes, as packages are downloaded in parallel.
+    static ref INDEX_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A single cached file.
+#[derive(Serialize, Deserialize, Clone)]
+struct CacheEntry {
+    /// Hash of the URL and expected digest of the file, which is also its file name
+    key: String,
+    url: String,
+    size: u64,
+    /// Seconds since the UNIX epoch at which this entry was last stored or used
+    last_used: u64,
+}
+
+/// Metadata about all files in the cache.
+#[derive(Serialize, Deserialize, Default)]
+struct CacheIndex {
+    entries: Vec<CacheEntry>,
+}
+
+/// A summary of the cache, as shown to the user.
+#[derive(Serialize)]
+pub struct CacheStatus {
+    pub path: String,
+    pub files: usize,
+    pub size: u64,
+    pub max_size: u64,
+}
+
+/// A size-limited cache of downloaded files, evicting the least recently used files
+/// first.
+pub struct DownloadCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+/// Returns the current time in seconds since the UNIX epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Places a copy of a file at a new path, replacing anything already there. Files are never
+/// hard linked, so that changes made after an install can't reach back into the cache.
+pub fn copy_file(source: &Path, target: &Path) -> Result<(), String> {
+    if target.exists() {
+        remove_file(target).map_err(|x| format!("Unable to replace {:?}: {:?}", target, x))?;
+    }
+
+    copy(source, target)
+        .map(|_| ())
+        .map_err(|x| format!("Unable to copy {:?} to {:?}: {:?}", source, target, x))
+}
+
+impl DownloadCache {
+    /// Opens (or creates) a cache in the specified directory. A maximum size of 0
+    /// disables the cache.
+    pub fn new(dir: PathBuf, max_size: u64) -> DownloadCache {
+        DownloadCache { dir, max_size }
+    }
+
+    /// Returns true if files will be stored in this cache.
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    /// Builds the key for a file, from its URL and expected digest.
+    fn key(url: &str, sha256: Option<&str>) -> String {
+        let identity = format!("{}\n{}", url, sha256.unwrap_or("").to_lowercase());
+
+        sha256_reader(&mut identity.as_bytes()).log_expect("Hashing a string cannot fail")
+    }
+
+    fn load_index(&self) -> CacheIndex {
+        let path = self.dir.join("index.json");
+
+        match File::open(&path) {
+            Ok(file) => serde_json::from_reader(file).unwrap_or_else(|x| {
+                warn!("Download cache index is corrupt, starting over: {:?}", x);
+                CacheIndex::default()
+            }),
+            Err(_) => CacheIndex::default(),
+        }
+    }
+
+    fn save_index(&self, index: &CacheIndex) -> Result<(), String> {
+        create_dir_all(&self.dir)
+            .map_err(|x| format!("Unable to create cache directory: {:?}", x))?;
+
+        let file = File::create(self.dir.join("index.json"))
+            .map_err(|x| format!("Unable to open cache index: {:?}", x))?;
+
+        serde_json::to_writer(file, index)
+            .map_err(|x| format!("Unable to write cache index: {:?}", x))
+    }
+
+    /// Copies a cached file to the target path, returning false if it isn't cached.
+    ///
+    /// Files without a digest are never cached, as a changed file at the same URL
+    /// couldn't be told apart from the cached one.
+    pub fn fetch(&self, url: &str, sha256: Option<&str>, target: &Path) -> Result<bool, String> {
+        if !self.is_enabled() || sha256.is_none() {
+            return Ok(false);
+        }
+
+        let _lock = INDEX_LOCK.lock().log_expect("Cache index lock has been poisoned");
+
+        let key = DownloadCache::key(url, sha256);
+        let mut index = self.load_index();
+
+        let source = self.dir.join(&key);
+
+        let position = match index.entries.iter().position(|x| x.key == key) {
+            Some(v) if source.exists() => v,
+            _ => return Ok(false),
+        };
+
+        copy_file(&source, target)?;
+
+        index.entries[position].last_used = now();
+        self.save_index(&index)?;
+
+        Ok(true)
+    }
+
+    /// Adds a downloaded file to the cache, evicting old files to make room.
+    pub fn store(&self, url: &str, sha256: Option<&str>, source: &Path) -> Result<(), String> {
+        if !self.is_enabled() || sha256.is_none() {
+            return Ok(());
+        }
+
+        let size = source
+            .metadata()
+            .map_err(|x| format!("Unable to read {:?}: {:?}", source, x))?
+            .len();
+
+        if size > self.max_size {
+            info!("{:?} is too large to be cached", source);
+            return Ok(());
+        }
+
+        let _lock = INDEX_LOCK.lock().log_expect("Cache index lock has been poisoned");
+
+        create_dir_all(&self.dir)
+            .map_err(|x| format!("Unable to create cache directory: {:?}", x))?;
+
+        let key = DownloadCache::key(url, sha256);
+        let mut index = self.load_index();
+
+        copy_file(source, &self.dir.join(&key))?;
+
+        index.entries.retain(|x| x.key != key);
+        // Kept first so that it wins ties with files used within the same second
+        index.entries.insert(
+            0,
+            CacheEntry {
+                key,
+                url: url.to_string(),
+                size,
+                last_used: now(),
+            },
+        );
+
+        // Evict the least recently used files until everything fits
+        index.entries.sort_by_key(|x| Reverse(x.last_used));
+
+        let mut total = 0;
+        let mut kept = Vec::new();
+
+        for entry in index.entries {
+            if total + entry.size <= self.max_size {
+                total += entry.size;
+                kept.push(entry);
+            } else {
+                info!("Evicting {:?} from the download cache", entry.url);
+
+                if let Err(v) = remove_file(self.dir.join(&entry.key)) {
+                    warn!("Unable to remove cached file: {:?}", v);
+                }
+            }
+        }
+
+        index.entries = kept;
+
+        self.save_index(&index)
+    }
+
+    /// Removes a single file from the cache, such as one which failed verification.
+    pub fn remove(&self, url: &str, sha256: Option<&str>) -> Result<(), String> {
+        let _lock = INDEX_LOCK.lock().log_expect("Cache index lock has been poisoned");
+
+        let key = DownloadCache::key(url, sha256);
+        let mut index = self.load_index();
+
+        index.entries.retain(|x| x.key != key);
+
+        let path = self.dir.join(&key);
+        if path.exists() {
+            remove_file(&path).map_err(|x| format!("Unable to remove cached file: {:?}", x))?;
+        }
+
+        self.save_index(&index)
+    }
+
+    /// Summarises what is currently stored in the cache.
+    pub fn status(&self) -> CacheStatus {
+        let _lock = INDEX_LOCK.lock().log_expect("Cache index lock has been poisoned");
+
+        let index = self.load_index();
+
+        CacheStatus {
+            path: self.dir.display().to_string(),
+            files: index.entries.len(),
+            size: index.entries.iter().map(|x| x.size).sum(),
+            max_size: self.max_size,
+        }
+    }
+
+    /// Removes everything from the cache.
+    pub fn clear(&self) -> Result<(), String> {
+        let _lock = INDEX_LOCK.lock().log_expect("Cache index lock has been poisoned");
+
+        if self.dir.exists() {
+            remove_dir_all(&self.dir)
+                .map_err(|x| format!("Unable to clear download cache: {:?}", x))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{read_to_string, write};
+
+    const URL: &str = "https://example.com/package.zip";
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("cache-test-{}-{}", name, ::std::process::id()));
+        if path.exists() {
+            remove_dir_all(&path).unwrap();
+        }
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn fetch_returns_stored_file() {
+        let dir = scratch_dir("fetch");
+        let cache = DownloadCache::new(dir.join("cache"), 1024);
+
+        write(dir.join("download"), "contents").unwrap();
+        cache
+            .store(URL, Some(SHA256), &dir.join("download"))
+            .unwrap();
+
+        assert!(cache
+            .fetch(URL, Some(SHA256), &dir.join("fetched"))
+            .unwrap());
+        assert_eq!(read_to_string(dir.join("fetched")).unwrap(), "contents");
+
+        assert!(!cache.fetch(URL, Some("other"), &dir.join("other")).unwrap());
+        assert!(!dir.join("other").exists());
+    }
+
+    #[test]
+    fn fetched_files_are_copies() {
+        let dir = scratch_dir("copies");
+        let cache = DownloadCache::new(dir.join("cache"), 1024);
+
+        write(dir.join("download"), "contents").unwrap();
+        cache
+            .store(URL, Some(SHA256), &dir.join("download"))
+            .unwrap();
+        cache
+            .fetch(URL, Some(SHA256), &dir.join("fetched"))
+            .unwrap();
+
+        write(dir.join("download"), "changed").unwrap();
+        write(dir.join("fetched"), "changed").unwrap();
+
+        cache
+            .fetch(URL, Some(SHA256), &dir.join("fetched"))
+            .unwrap();
+        assert_eq!(read_to_string(dir.join("fetched")).unwrap(), "contents");
+    }
+
+    #[test]
+    fn files_without_digest_are_not_cached() {
+        let dir = scratch_dir("no-digest");
+        let cache = DownloadCache::new(dir.join("cache"), 1024);
+
+        write(dir.join("download"), "contents").unwrap();
+        cache.store(URL, None, &dir.join("download")).unwrap();
+
+        assert!(!cache.fetch(URL, None, &dir.join("fetched")).unwrap());
+        assert!(!dir.join("fetched").exists());
+        assert_eq!(cache.status().files, 0);
+    }
+
+    #[test]
+    fn store_evicts_least_recently_used_files() {
+        let dir = scratch_dir("evict");
+        let cache = DownloadCache::new(dir.join("cache"), 10);
+
+        write(dir.join("download"), "123456").unwrap();
+        cache
+            .store("first", Some(SHA256), &dir.join("download"))
+            .unwrap();
+        cache
+            .store("second", Some(SHA256), &dir.join("download"))
+            .unwrap();
+
+        assert!(!cache
+            .fetch("first", Some(SHA256), &dir.join("fetched"))
+            .unwrap());
+        assert!(cache
+            .fetch("second", Some(SHA256), &dir.join("fetched"))
+            .unwrap());
+        assert_eq!(cache.status().files, 1);
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let dir = scratch_dir("disabled");
+        let cache = DownloadCache::new(dir.join("cache"), 0);
+
+        write(dir.join("download"), "contents").unwrap();
+        cache
+            .store(URL, Some(SHA256), &dir.join("download"))
+            .unwrap();
+
+        assert!(!cache
+            .fetch(URL, Some(SHA256), &dir.join("fetched"))
+            .unwrap());
+        assert!(!dir.join("cache").exists());
+    }
+}
@@ -0,0 +1,76 @@
+//! checksum.rs
+//!
+//! Contains helpers for computing and looking up SHA-256 digests of files.
+
+use sha2::{Digest, Sha256};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Computes the SHA-256 digest of the contents of a reader, as a lowercase hex string.
+pub fn sha256_reader<R: Read>(reader: &mut R) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = reader
+            .read(&mut buf)
+            .map_err(|x| format!("Failed to read data for hashing: {:?}", x))?;
+
+        if len == 0 {
+            break;
+        }
+
+        hasher.input(&buf[0..len]);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Computes the SHA-256 digest of a file on disk, as a lowercase hex string.
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file =
+        File::open(path).map_err(|x| format!("Failed to open {:?} for hashing: {:?}", path, x))?;
+
+    sha256_reader(&mut file)
+}
+
+/// Looks up the digest of a named file within a SHA256SUMS-style listing, where each
+/// line is a hex digest followed by a file name (optionally prefixed by '*').
+pub fn find_in_sums(sums: &str, name: &str) -> Option<String> {
+    for line in sums.lines() {
+        let mut parts = line.trim().splitn(2, char::is_whitespace);
+
+        let digest = match parts.next() {
+            Some(v) if v.len() == 64 && v.chars().all(|x| x.is_digit(16)) => v,
+            _ => continue,
+        };
+
+        let file_name = match parts.next() {
+            Some(v) => v.trim_start().trim_start_matches('*'),
+            None => continue,
+        };
+
+        if file_name == name {
+            return Some(digest.to_lowercase());
+        }
+    }
+
+    None
+}
+
+/// Checks that a file on disk matches an expected digest.
+pub fn verify_file(path: &Path, expected: &str) -> Result<(), String> {
+    let actual = sha256_file(path)?;
+
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Checksum mismatch (expected {}, got {})",
+            expected.trim().to_lowercase(),
+            actual
+        ))
+    }
+}
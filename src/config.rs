@@ -7,6 +7,11 @@ use toml::de::Error as TomlError;
 
 use serde_json::{self, Error as SerdeError};
 
+use regex::Regex;
+
+use std::collections::HashMap;
+use std::path::Path;
+
 use sources::get_by_name;
 use sources::types::Release;
 
@@ -17,6 +22,16 @@ pub struct PackageSource {
     #[serde(rename = "match")]
     pub match_regex: String,
     pub config: toml::Value,
+    /// Matches a release asset listing SHA-256 digests (e.g. "^SHA256SUMS$").
+    #[serde(default)]
+    pub checksums: Option<String>,
+    /// Known SHA-256 digests, keyed by file name.
+    #[serde(default)]
+    pub sha256: HashMap<String, String>,
+    /// Matches a release asset containing a delta from the installed version. "#FROM#"
+    /// is replaced with the installed version, and "#PLATFORM#" with the OS.
+    #[serde(default)]
+    pub delta: Option<String>,
 }
 
 /// Describes if/how a shortcut should be built for a package.
@@ -27,6 +42,56 @@ pub struct PackageShortcut {
     pub description: String,
 }
 
+/// Describes a package which is a single file (such as an AppImage), rather than an
+/// archive.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawFile {
+    /// Where the downloaded file is placed, relative to the install directory
+    pub relative_path: String,
+    #[serde(default = "default_executable")]
+    pub executable: bool,
+}
+
+fn default_executable() -> bool {
+    true
+}
+
+/// Commands run at points in a package's lifecycle, from the install directory.
+/// Install hooks also run when a package is updated.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PackageHooks {
+    #[serde(default)]
+    pub pre_install: Option<String>,
+    #[serde(default)]
+    pub post_install: Option<String>,
+    #[serde(default)]
+    pub pre_uninstall: Option<String>,
+    #[serde(default)]
+    pub post_uninstall: Option<String>,
+}
+
+/// Describes alternative locations for downloads whose URL starts with a prefix.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MirrorRule {
+    pub prefix: String,
+    /// Base URLs that replace the prefix
+    pub replacements: Vec<String>,
+}
+
+/// Describes a release channel (e.g. stable or nightly) that a package can follow.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PackageChannel {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Releases with a tag matching this regex belong to this channel.
+    #[serde(default)]
+    pub tag_match: Option<String>,
+    /// Releases with this prerelease flag belong to this channel.
+    #[serde(default)]
+    pub prerelease: Option<bool>,
+}
+
 /// Describes a overview of a individual package.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PackageDescription {
@@ -36,6 +101,138 @@ pub struct PackageDescription {
     pub source: PackageSource,
     #[serde(default)]
     pub shortcuts: Vec<PackageShortcut>,
+    /// Channels this package can be installed from. The first is the default.
+    #[serde(default)]
+    pub channels: Vec<PackageChannel>,
+    /// Restricts which releases may be installed (e.g. "~1.4" or "=1.4.2").
+    #[serde(default)]
+    pub pin: Option<String>,
+    /// Packages which are installed alongside this one.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Packages which can't be installed at the same time as this one.
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Commands which run before and after this package is installed or uninstalled.
+    #[serde(default)]
+    pub hooks: PackageHooks,
+    /// Glob patterns of installed paths which users may edit, matched like `include`.
+    /// Edited files are kept on update, with the new default written next to them as
+    /// "PATH.new".
+    #[serde(default)]
+    pub config_files: Vec<String>,
+    /// Installs the downloaded file as-is, instead of extracting it.
+    #[serde(default)]
+    pub raw: Option<RawFile>,
+    /// Number of leading directories to remove from paths in the archive.
+    #[serde(default)]
+    pub strip_components: usize,
+    /// Only extracts this directory of the archive (after stripping), as the root.
+    #[serde(default)]
+    pub subdirectory: Option<String>,
+    /// Glob patterns of paths to extract. Everything is extracted if empty. `*` only matches
+    /// within a directory, and `**` matches across them.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns of paths to leave out, even if they are included.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Keeps setuid, setgid and sticky bits from the archive, which are otherwise cleared.
+    #[serde(default)]
+    pub allow_special_modes: bool,
+}
+
+/// Settings for how the installer connects to the network. Unset values fall back
+/// to the bootstrap config, then the environment, then built-in defaults.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct NetworkSettings {
+    /// A proxy URL for all requests. Defaults to HTTPS_PROXY/HTTP_PROXY/ALL_PROXY.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub proxy_username: Option<String>,
+    #[serde(default)]
+    pub proxy_password: Option<String>,
+    /// Comma separated hosts which bypass the proxy. Defaults to NO_PROXY.
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    /// Extra trusted root certificates, as paths to PEM/DER files or inline PEM.
+    #[serde(default)]
+    pub ca_certificates: Vec<String>,
+    /// Seconds to wait for a connection to be established.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for any individual read or write.
+    #[serde(default)]
+    pub read_timeout: Option<u64>,
+    /// How many times a failed request is retried.
+    #[serde(default)]
+    pub retries: Option<u32>,
+    /// Milliseconds to wait before the first retry, doubling each time.
+    #[serde(default)]
+    pub retry_delay: Option<u64>,
+}
+
+impl NetworkSettings {
+    /// Combines these settings with overrides, preferring any values set in them.
+    pub fn merge(&self, overrides: &NetworkSettings) -> NetworkSettings {
+        let mut ca_certificates = self.ca_certificates.clone();
+        ca_certificates.extend(overrides.ca_certificates.iter().cloned());
+
+        NetworkSettings {
+            proxy: overrides.proxy.clone().or_else(|| self.proxy.clone()),
+            proxy_username: overrides
+                .proxy_username
+                .clone()
+                .or_else(|| self.proxy_username.clone()),
+            proxy_password: overrides
+                .proxy_password
+                .clone()
+                .or_else(|| self.proxy_password.clone()),
+            no_proxy: overrides.no_proxy.clone().or_else(|| self.no_proxy.clone()),
+            ca_certificates,
+            connect_timeout: overrides.connect_timeout.or(self.connect_timeout),
+            read_timeout: overrides.read_timeout.or(self.read_timeout),
+            retries: overrides.retries.or(self.retries),
+            retry_delay: overrides.retry_delay.or(self.retry_delay),
+        }
+    }
+}
+
+/// Limits on what may be extracted from a package, protecting against archive bombs.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ExtractionLimits {
+    /// Maximum total size of the extracted files in megabytes
+    #[serde(default = "default_max_extracted_size")]
+    pub max_size: u64,
+    /// Maximum number of files, directories and links in a package
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Maximum ratio between the extracted size and the size of the archive
+    #[serde(default = "default_max_ratio")]
+    pub max_ratio: u64,
+}
+
+fn default_max_extracted_size() -> u64 {
+    16384
+}
+
+fn default_max_entries() -> usize {
+    100_000
+}
+
+fn default_max_ratio() -> u64 {
+    250
+}
+
+impl Default for ExtractionLimits {
+    fn default() -> Self {
+        ExtractionLimits {
+            max_size: default_max_extracted_size(),
+            max_entries: default_max_entries(),
+            max_ratio: default_max_ratio(),
+        }
+    }
 }
 
 /// Describes the application itself.
@@ -43,6 +240,15 @@ pub struct PackageDescription {
 pub struct BaseAttributes {
     pub name: String,
     pub target_url: String,
+    /// A minisign public key used to verify the config and packages.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Accept content which has no signature. Only intended for development builds.
+    #[serde(default)]
+    pub allow_unsigned: bool,
+    /// Proxy, certificate and timeout settings, which may contain credentials.
+    #[serde(default, skip_serializing)]
+    pub network: NetworkSettings,
 }
 
 impl BaseAttributes {
@@ -66,6 +272,29 @@ pub struct Config {
     pub packages: Vec<PackageDescription>,
     #[serde(default)]
     pub hide_advanced: bool,
+    /// Rules for finding mirrors of package downloads
+    #[serde(default)]
+    pub mirrors: Vec<MirrorRule>,
+    /// How many packages may be downloaded at the same time
+    #[serde(default = "default_max_parallel_downloads")]
+    pub max_parallel_downloads: usize,
+    /// Maximum size of the shared download cache in megabytes. 0 disables it.
+    #[serde(default = "default_download_cache_size")]
+    pub download_cache_size: u64,
+    /// Overrides for the network settings in the bootstrap config
+    #[serde(default, skip_serializing)]
+    pub network: NetworkSettings,
+    /// Limits applied when extracting packages
+    #[serde(default)]
+    pub extraction_limits: ExtractionLimits,
+}
+
+fn default_max_parallel_downloads() -> usize {
+    3
+}
+
+pub fn default_download_cache_size() -> u64 {
+    2048
 }
 
 impl Config {
@@ -80,14 +309,58 @@ impl Config {
     }
 }
 
+impl MirrorRule {
+    /// Returns the mirrored equivalents of a URL, if this rule applies to it.
+    pub fn apply(&self, url: &str) -> Vec<String> {
+        if !url.starts_with(&self.prefix) {
+            return Vec::new();
+        }
+
+        let remainder = &url[self.prefix.len()..];
+
+        self.replacements
+            .iter()
+            .map(|x| format!("{}{}", x, remainder))
+            .collect()
+    }
+}
+
+impl PackageChannel {
+    /// Checks if a release belongs to this channel. Channels explicitly declared by the
+    /// source take priority over tag patterns and prerelease flags.
+    pub fn matches(&self, release: &Release) -> Result<bool, String> {
+        if let Some(ref channel) = release.channel {
+            return Ok(channel == &self.name);
+        }
+
+        if let Some(ref tag_match) = self.tag_match {
+            let regex = Regex::new(tag_match)
+                .map_err(|x| format!("An error occurred while compiling regex: {:?}", x))?;
+
+            match release.tag {
+                Some(ref tag) if regex.is_match(tag) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        if let Some(prerelease) = self.prerelease {
+            if prerelease != release.prerelease {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
 impl PackageSource {
     /// Fetches releases for a given package
-    pub fn get_current_releases(&self) -> Result<Vec<Release>, String> {
+    pub fn get_current_releases(&self, cache_dir: Option<&Path>) -> Result<Vec<Release>, String> {
         let package_handler = match get_by_name(&self.name) {
             Some(v) => v,
             _ => return Err(format!("Handler {} not found", self.name)),
         };
 
-        package_handler.get_current_releases(&self.config)
+        package_handler.get_current_releases(&self.config, cache_dir)
     }
 }
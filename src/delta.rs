@@ -0,0 +1,503 @@
+//! delta.rs
+//!
+//! Applies delta updates, which turn an installed version of a package into a newer one
+//! without downloading the full archive.
+//!
+//! A delta is an archive containing:
+//! - `delta.json`: a `DeltaManifest` describing what changed
+//! - `patches/PATH`: binary patches (in the uncompressed bsdiff-rs format) for files
+//!   which changed
+//! - `files/PATH`: complete copies of files which were added or replaced
+
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_link, remove_dir_all, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use archives;
+use archives::{check_path, EntryKind, ExtractionGuard};
+
+use config::ExtractionLimits;
+
+use cache::copy_file;
+
+use checksum::sha256_reader;
+
+use native::create_symlink;
+
+use sources::types::Version;
+
+/// What happened to an individual file between versions.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaAction {
+    /// The file changed, and has a patch in `patches/`
+    Patch,
+    /// The file was added or replaced, and has a copy in `files/`
+    Add,
+    /// The file was removed
+    Delete,
+}
+
+/// A change to a single file, relative to the install directory.
+#[derive(Deserialize, Debug)]
+pub struct DeltaEntry {
+    pub path: String,
+    pub action: DeltaAction,
+    /// Digest of the file after the change has been applied. Required for patched and
+    /// added files
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Describes a delta between two versions of a package.
+#[derive(Deserialize, Debug)]
+pub struct DeltaManifest {
+    pub from: String,
+    pub to: String,
+    pub files: Vec<DeltaEntry>,
+}
+
+/// Decodes a signed 64-bit integer as written by bsdiff (sign-magnitude, little endian).
+fn offtin(buf: &[u8]) -> i64 {
+    let mut value = i64::from(buf[7] & 0x7F);
+    for byte in buf[0..7].iter().rev() {
+        value = (value << 8) | i64::from(*byte);
+    }
+
+    if buf[7] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Fills a buffer from a reader, returning false if the reader was already exhausted.
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, String> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let len = reader
+            .read(&mut buf[filled..])
+            .map_err(|x| format!("Failed to read patch: {:?}", x))?;
+
+        if len == 0 {
+            if filled == 0 {
+                return Ok(false);
+            }
+
+            return Err("Patch is truncated".to_string());
+        }
+
+        filled += len;
+    }
+
+    Ok(true)
+}
+
+/// Applies a bsdiff patch to a file's old contents, returning its new contents. Patches
+/// which would produce more than `max_len` bytes are rejected.
+pub fn apply_patch<R: Read>(old: &[u8], patch: &mut R, max_len: u64) -> Result<Vec<u8>, String> {
+    let mut new = Vec::new();
+    let mut old_position: i64 = 0;
+
+    let mut control = [0u8; 24];
+
+    while read_block(patch, &mut control)? {
+        let add_len = offtin(&control[0..8]);
+        let copy_len = offtin(&control[8..16]);
+        let seek_len = offtin(&control[16..24]);
+
+        if add_len < 0 || copy_len < 0 {
+            return Err("Patch contains a negative length".to_string());
+        }
+
+        if new.len() as u64 + add_len as u64 + copy_len as u64 > max_len {
+            return Err(format!("Patch produces more than {} bytes", max_len));
+        }
+
+        // Bytes from the patch are added to the old file's bytes
+        let mut diff = vec![0u8; add_len as usize];
+        if !read_block(patch, &mut diff)? && add_len > 0 {
+            return Err("Patch is truncated".to_string());
+        }
+
+        for (i, byte) in diff.into_iter().enumerate() {
+            let position = old_position + i as i64;
+            let old_byte = if position >= 0 && (position as usize) < old.len() {
+                old[position as usize]
+            } else {
+                0
+            };
+
+            new.push(byte.wrapping_add(old_byte));
+        }
+
+        old_position += add_len;
+
+        // Followed by bytes which are copied verbatim
+        let mut extra = vec![0u8; copy_len as usize];
+        if !read_block(patch, &mut extra)? && copy_len > 0 {
+            return Err("Patch is truncated".to_string());
+        }
+
+        new.extend_from_slice(&extra);
+
+        old_position += seek_len;
+    }
+
+    Ok(new)
+}
+
+/// Checks data against an expected digest, which changed files must always have.
+fn check_digest(path: &Path, data: &[u8], expected: &Option<String>) -> Result<(), String> {
+    let expected = match *expected {
+        Some(ref v) => v,
+        None => return Err(format!("Delta has no digest for {:?}", path)),
+    };
+
+    let actual = sha256_reader(&mut &data[..])?;
+
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} doesn't match after being updated (expected {}, got {})",
+            path, expected, actual
+        ))
+    }
+}
+
+/// Writes data to a file, creating any parent directories.
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+    }
+
+    File::create(path)
+        .and_then(|mut x| x.write_all(data))
+        .map_err(|x| format!("Unable to write {:?}: {:?}", path, x))
+}
+
+/// Reads the entirety of a file into memory.
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+
+    File::open(path)
+        .and_then(|mut x| x.read_to_end(&mut data))
+        .map_err(|x| format!("Unable to read {:?}: {:?}", path, x))?;
+
+    Ok(data)
+}
+
+/// Where a delta is read from, the install it applies to, and where the result goes.
+pub struct DeltaPaths<'a> {
+    /// The downloaded delta archive
+    pub archive: &'a Path,
+    /// The directory of the existing install
+    pub install_dir: &'a Path,
+    /// Files of the existing install, relative to its directory
+    pub installed_files: &'a [String],
+    /// Where the complete file tree of the new version is built
+    pub target_dir: &'a Path,
+}
+
+/// Builds the complete file tree of a new version in `paths.target_dir`, from the files
+/// of an existing install and a delta archive. Returns the files which were carried
+/// over from the install without being changed.
+pub fn apply_delta(
+    name: &str,
+    paths: &DeltaPaths,
+    installed_version: &Version,
+    target_version: &Version,
+    limits: &ExtractionLimits,
+) -> Result<Vec<String>, String> {
+    let target_dir = paths.target_dir;
+
+    // Unpack the delta next to the target, so patches don't need to be held in memory
+    let mut work_dir = target_dir.as_os_str().to_owned();
+    work_dir.push(".delta");
+    let work_dir = PathBuf::from(work_dir);
+    if work_dir.exists() {
+        remove_dir_all(&work_dir).map_err(|x| format!("Unable to clean up: {:?}", x))?;
+    }
+
+    let mut manifest = None;
+
+    {
+        let mut archive = archives::read_archive(name, paths.archive)?;
+
+        let archive_size = paths.archive.metadata().ok().map(|x| x.len());
+        let mut guard = ExtractionGuard::new(limits.clone(), archive_size);
+
+        archive.for_each(&mut |_, _, entry, reader| {
+            if entry.kind != EntryKind::File {
+                return Ok(());
+            }
+
+            guard.add_entry()?;
+
+            let path = entry.path;
+
+            if path == Path::new("delta.json") {
+                let mut buf = Vec::new();
+                guard.copy(reader, &mut buf)?;
+
+                let parsed: DeltaManifest = serde_json::from_slice(&buf)
+                    .map_err(|x| format!("Failed to parse delta.json: {:?}", x))?;
+                manifest = Some(parsed);
+
+                return Ok(());
+            }
+
+            let target = work_dir.join(&path);
+
+            if let Some(parent) = target.parent() {
+                create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+            }
+
+            let mut file = File::create(&target)
+                .map_err(|x| format!("Unable to open file handle: {:?}", x))?;
+
+            guard.copy(reader, &mut file).map(|_| ())
+        })?;
+    }
+
+    let manifest = manifest.ok_or_else(|| "Delta is missing delta.json".to_string())?;
+
+    if Version::parse(&manifest.from).as_ref() != Some(installed_version) {
+        return Err(format!(
+            "Delta is for version {}, but {} is installed",
+            manifest.from, installed_version
+        ));
+    }
+
+    if Version::parse(&manifest.to).as_ref() != Some(target_version) {
+        return Err(format!(
+            "Delta updates to version {}, but {} is being installed",
+            manifest.to, target_version
+        ));
+    }
+
+    info!(
+        "Applying delta from {} to {} ({} change(s))",
+        manifest.from,
+        manifest.to,
+        manifest.files.len()
+    );
+
+    let mut changes = HashMap::new();
+    for entry in &manifest.files {
+        changes.insert(check_path(Path::new(&entry.path))?, entry);
+    }
+
+    create_dir_all(target_dir).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+
+    // Patched files never pass through an archive, so are limited separately
+    let mut output = ExtractionGuard::new(limits.clone(), None);
+
+    let mut unchanged = Vec::new();
+
+    // Carry over (and patch) files from the existing install
+    for file in paths.installed_files {
+        let relative = PathBuf::from(file);
+        let source = paths.install_dir.join(&relative);
+        let target = target_dir.join(&relative);
+
+        // Links are carried over as-is, rather than duplicating what they point to
+        if let Ok(link) = read_link(&source) {
+            if !changes.contains_key(&relative) {
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+                }
+
+                create_symlink(&link, &target)?;
+                unchanged.push(file.clone());
+            }
+
+            continue;
+        }
+
+        if !source.is_file() {
+            continue;
+        }
+
+        match changes.get(&relative) {
+            None => {
+                if let Some(parent) = target.parent() {
+                    create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+                }
+
+                copy_file(&source, &target)?;
+                unchanged.push(file.clone());
+            }
+            Some(entry) if entry.action == DeltaAction::Patch => {
+                let old = read_file(&source)?;
+
+                let patch_path = work_dir.join("patches").join(&relative);
+                let mut patch = File::open(&patch_path)
+                    .map_err(|x| format!("Delta is missing a patch for {:?}: {:?}", relative, x))?;
+
+                let new = apply_patch(&old, &mut patch, output.remaining())?;
+                output.add_bytes(new.len() as u64)?;
+                check_digest(&relative, &new, &entry.sha256)?;
+
+                write_file(&target, &new)?;
+            }
+            // Added files are handled below, and deleted files are left out
+            Some(_) => {}
+        }
+    }
+
+    for entry in &manifest.files {
+        let relative = check_path(Path::new(&entry.path))?;
+
+        match entry.action {
+            DeltaAction::Add => {
+                let data = read_file(&work_dir.join("files").join(&relative))?;
+                check_digest(&relative, &data, &entry.sha256)?;
+
+                write_file(&target_dir.join(&relative), &data)?;
+            }
+            DeltaAction::Patch => {
+                if !target_dir.join(&relative).exists() {
+                    return Err(format!(
+                        "Delta patches {:?}, which isn't installed",
+                        relative
+                    ));
+                }
+            }
+            DeltaAction::Delete => {}
+        }
+    }
+
+    if let Err(v) = remove_dir_all(&work_dir) {
+        warn!("Unable to clean up delta files: {:?}", v);
+    }
+
+    Ok(unchanged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes an integer as bsdiff does, the reverse of `offtin`.
+    fn offout(value: i64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut magnitude = value.abs();
+
+        for _ in 0..8 {
+            buf.push((magnitude & 0xFF) as u8);
+            magnitude >>= 8;
+        }
+
+        if value < 0 {
+            buf[7] |= 0x80;
+        }
+
+        buf
+    }
+
+    /// Builds a patch from (diff bytes, extra bytes, seek) blocks.
+    fn patch(blocks: &[(&[u8], &[u8], i64)]) -> Vec<u8> {
+        let mut patch = Vec::new();
+
+        for &(diff, extra, seek) in blocks {
+            patch.extend(offout(diff.len() as i64));
+            patch.extend(offout(extra.len() as i64));
+            patch.extend(offout(seek));
+            patch.extend_from_slice(diff);
+            patch.extend_from_slice(extra);
+        }
+
+        patch
+    }
+
+    fn apply(old: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+        apply_patch(old, &mut &patch[..], 1024)
+    }
+
+    #[test]
+    fn offtin_decodes_sign_magnitude() {
+        assert_eq!(offtin(&offout(0)), 0);
+        assert_eq!(offtin(&offout(300)), 300);
+        assert_eq!(offtin(&offout(-300)), -300);
+        assert_eq!(offtin(&offout(i64::max_value())), i64::max_value());
+    }
+
+    #[test]
+    fn apply_patch_adds_diff_to_old_bytes() {
+        assert_eq!(
+            apply(b"abc", &patch(&[(&[0, 0, 0], b"", 0)])),
+            Ok(b"abc".to_vec())
+        );
+        assert_eq!(
+            apply(b"abc", &patch(&[(&[1, 1, 1], b"!", 0)])),
+            Ok(b"bcd!".to_vec())
+        );
+        assert_eq!(apply(b"abc", &[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn apply_patch_seeks_within_old_file() {
+        let skip = patch(&[(&[0, 0], b"", 2), (&[0, 0], b"", 0)]);
+        assert_eq!(apply(b"abcdef", &skip), Ok(b"abef".to_vec()));
+
+        let repeat = patch(&[(&[0, 0, 0], b"", -3), (&[0, 0, 0], b"", 0)]);
+        assert_eq!(apply(b"abc", &repeat), Ok(b"abcabc".to_vec()));
+    }
+
+    #[test]
+    fn apply_patch_treats_bytes_outside_old_file_as_zero() {
+        assert_eq!(apply(b"a", &patch(&[(&[0, 5], b"", 0)])), Ok(vec![b'a', 5]));
+        assert_eq!(
+            apply(b"a", &patch(&[(&[], b"", -5), (&[7], b"", 0)])),
+            Ok(vec![7])
+        );
+    }
+
+    #[test]
+    fn apply_patch_rejects_truncated_patches() {
+        let full = patch(&[(&[0, 0, 0], b"xyz", 0)]);
+
+        for len in &[10, 24, 26, 28] {
+            assert!(apply(b"abc", &full[0..*len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn apply_patch_rejects_negative_lengths() {
+        let mut negative = offout(-1);
+        negative.extend(offout(0));
+        negative.extend(offout(0));
+
+        assert!(apply(b"abc", &negative).is_err());
+    }
+
+    #[test]
+    fn apply_patch_limits_output_size() {
+        let block = patch(&[(&[0; 4], &[0; 4], 0)]);
+        assert_eq!(apply_patch(b"", &mut &block[..], 8).map(|x| x.len()), Ok(8));
+        assert!(apply_patch(b"", &mut &block[..], 7).is_err());
+
+        // Checked before anything is allocated for the block
+        let mut huge = offout(1 << 40);
+        huge.extend(offout(0));
+        huge.extend(offout(0));
+        assert!(apply_patch(b"", &mut &huge[..], 1024).is_err());
+    }
+
+    #[test]
+    fn check_digest_requires_a_digest() {
+        let path = Path::new("a");
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        assert!(check_digest(path, b"abc", &Some(digest.to_string())).is_ok());
+        assert!(check_digest(path, b"abc", &Some(digest.to_uppercase())).is_ok());
+        assert!(check_digest(path, b"abd", &Some(digest.to_string())).is_err());
+        assert!(check_digest(path, b"abc", &None).is_err());
+    }
+}
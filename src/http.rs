@@ -1,13 +1,225 @@
 //! http.rs
 //!
-//! A simple wrapper around Hyper's HTTP client.
+//! A simple wrapper around Hyper's HTTP client. Local file:// and embedded:// URLs are
+//! also accepted for offline installs.
 
-use reqwest::header::CONTENT_LENGTH;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
 
+use std::env::var;
+use std::fs::File;
 use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::sync::RwLock;
+use std::thread;
 use std::time::Duration;
 
-use reqwest::Client;
+use reqwest::{Certificate, Client, Proxy, Url as ProxyUrl};
+
+use url::Url;
+
+use config::NetworkSettings;
+
+use logging::LoggingErrors;
+
+use sources::embedded;
+
+/// Seconds to wait for a connection, unless configured otherwise.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+/// Seconds to wait for an individual read or write, unless configured otherwise.
+const DEFAULT_READ_TIMEOUT: u64 = 30;
+/// Times to retry a failed request, unless configured otherwise.
+const DEFAULT_RETRIES: u32 = 3;
+/// Milliseconds to wait before the first retry, unless configured otherwise.
+const DEFAULT_RETRY_DELAY: u64 = 500;
+/// The longest time to wait between retries, in milliseconds.
+const MAX_RETRY_DELAY: u64 = 30_000;
+
+lazy_static! {
+    static ref SETTINGS: RwLock<NetworkSettings> = RwLock::new(NetworkSettings::default());
+}
+
+/// Replaces the network settings used by all subsequent requests.
+pub fn configure(settings: NetworkSettings) {
+    *SETTINGS
+        .write()
+        .log_expect("Network settings have been poisoned") = settings;
+}
+
+/// Returns a copy of the current network settings.
+fn current_settings() -> NetworkSettings {
+    SETTINGS
+        .read()
+        .log_expect("Network settings have been poisoned")
+        .clone()
+}
+
+/// A failed request, and if it is worth trying again.
+struct RequestError {
+    message: String,
+    transient: bool,
+}
+
+impl RequestError {
+    fn transient(message: String) -> Self {
+        RequestError {
+            message,
+            transient: true,
+        }
+    }
+
+    fn fatal(message: String) -> Self {
+        RequestError {
+            message,
+            transient: false,
+        }
+    }
+}
+
+/// Checks a response status, treating server errors and rate limiting as transient.
+fn check_status(status: StatusCode) -> Result<(), RequestError> {
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let message = format!("Bad status code: {:?}.", status);
+
+    if status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+    {
+        Err(RequestError::transient(message))
+    } else {
+        Err(RequestError::fatal(message))
+    }
+}
+
+/// Runs a request, retrying transient failures with exponential backoff.
+fn with_retries<T, F>(url: &str, mut request: F) -> Result<T, String>
+where
+    F: FnMut() -> Result<T, RequestError>,
+{
+    let settings = current_settings();
+    let retries = settings.retries.unwrap_or(DEFAULT_RETRIES);
+    let mut delay = settings.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY);
+
+    let mut attempt = 0;
+
+    loop {
+        match request() {
+            Ok(v) => return Ok(v),
+            Err(ref v) if v.transient && attempt < retries => {
+                attempt += 1;
+
+                warn!(
+                    "Request to {:?} failed ({}), retrying in {}ms ({} of {})",
+                    url, v.message, delay, attempt, retries
+                );
+
+                thread::sleep(Duration::from_millis(delay));
+                delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+            }
+            Err(v) => return Err(v.message),
+        }
+    }
+}
+
+/// Reads the first non-empty environment variable out of the given names.
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|x| var(x).ok())
+        .find(|x| !x.trim().is_empty())
+}
+
+/// Builds a proxy from the configuration or environment, if one is set.
+fn build_proxy(settings: &NetworkSettings) -> Result<Option<Proxy>, String> {
+    let proxy = settings.proxy.clone().or_else(|| {
+        env_var(&[
+            "HTTPS_PROXY",
+            "https_proxy",
+            "HTTP_PROXY",
+            "http_proxy",
+            "ALL_PROXY",
+            "all_proxy",
+        ])
+    });
+
+    let proxy = match proxy {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let proxy_url =
+        ProxyUrl::parse(&proxy).map_err(|x| format!("Invalid proxy URL {:?}: {:?}", proxy, x))?;
+
+    let no_proxy = settings
+        .no_proxy
+        .clone()
+        .or_else(|| env_var(&["NO_PROXY", "no_proxy"]))
+        .unwrap_or_default();
+
+    let bypass: Vec<String> = no_proxy
+        .split(',')
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    if bypass.iter().any(|x| x == "*") {
+        return Ok(None);
+    }
+
+    // Entries match the host itself, as well as any subdomains
+    let bypass: Vec<String> = bypass
+        .into_iter()
+        .map(|x| x.trim_start_matches('*').trim_start_matches('.').to_string())
+        .collect();
+
+    info!("Using proxy {:?}", proxy_url.as_str());
+
+    let mut proxy = Proxy::custom(move |url| {
+        let host = url.host_str().unwrap_or("").to_lowercase();
+
+        if bypass
+            .iter()
+            .any(|x| host == *x || host.ends_with(&format!(".{}", x)))
+        {
+            None
+        } else {
+            Some(proxy_url.clone())
+        }
+    });
+
+    if let Some(ref username) = settings.proxy_username {
+        let password = settings
+            .proxy_password
+            .as_ref()
+            .map(|x| x.as_str())
+            .unwrap_or("");
+
+        proxy = proxy.basic_auth(username, password);
+    }
+
+    Ok(Some(proxy))
+}
+
+/// Loads a trusted root certificate from a PEM/DER file, or from inline PEM.
+fn load_certificate(source: &str) -> Result<Certificate, String> {
+    if source.trim_start().starts_with("-----BEGIN") {
+        return Certificate::from_pem(source.as_bytes())
+            .map_err(|x| format!("Invalid inline certificate: {:?}", x));
+    }
+
+    let mut data = Vec::new();
+    File::open(source)
+        .and_then(|mut x| x.read_to_end(&mut data))
+        .map_err(|x| format!("Failed to read certificate {:?}: {:?}", source, x))?;
+
+    Certificate::from_pem(&data)
+        .or_else(|_| Certificate::from_der(&data))
+        .map_err(|x| format!("Invalid certificate {:?}: {:?}", source, x))
+}
 
 /// Asserts that a URL is valid HTTPS, else returns an error.
 pub fn assert_ssl(url: &str) -> Result<(), String> {
@@ -18,52 +230,248 @@ pub fn assert_ssl(url: &str) -> Result<(), String> {
     }
 }
 
+/// Returns true if the URL points at the local filesystem, or a payload bundled
+/// inside of the installer.
+pub fn is_local(url: &str) -> bool {
+    url.starts_with("file://") || url.starts_with(embedded::URL_PREFIX)
+}
+
+/// Looks up a payload bundled inside of the installer by its embedded:// URL.
+fn open_embedded_file(url: &str) -> Result<&'static [u8], String> {
+    embedded::get_payload(&url[embedded::URL_PREFIX.len()..])
+        .ok_or_else(|| format!("No payload named {:?} is embedded in this installer", url))
+}
+
+/// Opens a file referenced by a file:// URL.
+fn open_local_file(url: &str) -> Result<File, String> {
+    let path = Url::parse(url)
+        .map_err(|x| format!("Invalid file URL: {:?}", x))?
+        .to_file_path()
+        .map_err(|_| format!("URL {:?} is not a valid local path", url))?;
+
+    File::open(&path).map_err(|x| format!("Failed to open {:?}: {:?}", path, x))
+}
+
 /// Builds a customised HTTP client.
 pub fn build_client() -> Result<Client, String> {
-    Client::builder()
-        .timeout(Duration::from_secs(8))
+    let settings = current_settings();
+
+    let connect_timeout = settings
+        .connect_timeout
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let read_timeout = settings.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT);
+
+    // The timeout applies to each read, rather than the whole body
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(connect_timeout))
+        .timeout(Duration::from_secs(read_timeout));
+
+    if let Some(proxy) = build_proxy(&settings)? {
+        builder = builder.proxy(proxy);
+    }
+
+    for certificate in &settings.ca_certificates {
+        builder = builder.add_root_certificate(load_certificate(certificate)?);
+    }
+
+    builder
         .build()
         .map_err(|x| format!("Unable to build client: {:?}", x))
 }
 
 /// Downloads a text file from the specified URL.
 pub fn download_text(url: &str) -> Result<String, String> {
+    if url.starts_with(embedded::URL_PREFIX) {
+        return String::from_utf8(open_embedded_file(url)?.to_vec())
+            .map_err(|x| format!("Embedded file was not valid UTF-8: {:?}", x));
+    }
+
+    if is_local(url) {
+        let mut buf = String::new();
+        open_local_file(url)?
+            .read_to_string(&mut buf)
+            .map_err(|x| format!("Failed to read local file: {:?}", x))?;
+        return Ok(buf);
+    }
+
     assert_ssl(url)?;
 
-    let mut client = build_client()?
-        .get(url)
-        .send()
-        .map_err(|x| format!("Failed to GET resource: {:?}", x))?;
+    with_retries(url, || {
+        let mut client = build_client()
+            .map_err(RequestError::fatal)?
+            .get(url)
+            .send()
+            .map_err(|x| RequestError::transient(format!("Failed to GET resource: {:?}", x)))?;
+
+        check_status(client.status())?;
 
-    client
-        .text()
-        .map_err(|v| format!("Failed to get text from resource: {:?}", v))
+        client.text().map_err(|v| {
+            RequestError::transient(format!("Failed to get text from resource: {:?}", v))
+        })
+    })
 }
 
-/// Streams a file from a HTTP server.
+/// Streams a file from a HTTP server, or from the local filesystem for file:// URLs.
 pub fn stream_file<F>(url: &str, mut callback: F) -> Result<(), String>
 where
     F: FnMut(Vec<u8>, u64) -> (),
 {
+    let mut delivered: u64 = 0;
+
+    stream_file_from(url, 0, |data, position, size| {
+        // Skip anything already delivered, in case a retry restarted the transfer
+        let skip = delivered.saturating_sub(position) as usize;
+
+        if skip < data.len() {
+            delivered = position + data.len() as u64;
+            callback(if skip > 0 { data[skip..].to_vec() } else { data }, size);
+        }
+
+        Ok(())
+    })
+}
+
+/// Streams a file starting from the specified byte offset, using a HTTP Range request
+/// where possible.
+///
+/// The callback is given each chunk, the offset of that chunk within the file and the
+/// total size of the file (0 if unknown), and may abort the transfer by returning an
+/// error. Servers may ignore the Range header, in which case the first chunk will be at
+/// offset 0 regardless of the requested offset.
+pub fn stream_file_from<F>(url: &str, offset: u64, mut callback: F) -> Result<(), String>
+where
+    F: FnMut(Vec<u8>, u64, u64) -> Result<(), String>,
+{
+    if url.starts_with(embedded::URL_PREFIX) {
+        let data = open_embedded_file(url)?;
+        let size = data.len() as u64;
+        let offset = offset.min(size);
+
+        return stream_reader(&data[offset as usize..], offset, size, &mut callback);
+    }
+
+    if is_local(url) {
+        let mut file = open_local_file(url)?;
+        let size = file
+            .metadata()
+            .map_err(|x| format!("Failed to read local file metadata: {:?}", x))?
+            .len();
+        let offset = offset.min(size);
+
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|x| format!("Failed to seek in local file: {:?}", x))?;
+
+        return stream_reader(file, offset, size, &mut callback);
+    }
+
     assert_ssl(url)?;
 
-    let mut client = build_client()?
-        .get(url)
+    // Interrupted transfers are retried from where they stopped
+    let mut position = offset;
+
+    with_retries(url, || {
+        let mut aborted = false;
+
+        let result = stream_response(url, position, &mut |data, chunk_position, size| {
+            let len = data.len() as u64;
+
+            callback(data, chunk_position, size).map_err(|x| {
+                aborted = true;
+                x
+            })?;
+
+            position = chunk_position + len;
+
+            Ok(())
+        });
+
+        // Errors raised by the callback itself shouldn't be retried
+        result.map_err(|x| RequestError {
+            message: x.message,
+            transient: x.transient && !aborted,
+        })
+    })
+}
+
+/// Performs a single (possibly ranged) request, streaming the response to a callback.
+fn stream_response<F>(url: &str, offset: u64, callback: &mut F) -> Result<(), RequestError>
+where
+    F: FnMut(Vec<u8>, u64, u64) -> Result<(), String>,
+{
+    let mut request = build_client().map_err(RequestError::fatal)?.get(url);
+
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset).as_str());
+    }
+
+    let response = request
         .send()
-        .map_err(|x| format!("Failed to GET resource: {:?}", x))?;
+        .map_err(|x| RequestError::transient(format!("Failed to GET resource: {:?}", x)))?;
 
-    let size = match client.headers().get(CONTENT_LENGTH) {
+    check_status(response.status())?;
+
+    let length = match response.headers().get(CONTENT_LENGTH) {
         Some(ref v) => v
             .to_str()
-            .map_err(|x| format!("Content length header was invalid: {:?}", x))?
-            .parse()
-            .map_err(|x| format!("Failed to parse content length: {:?}", x))?,
+            .map_err(|x| format!("Content length header was invalid: {:?}", x))
+            .and_then(|x| {
+                x.parse()
+                    .map_err(|x| format!("Failed to parse content length: {:?}", x))
+            }).map_err(RequestError::fatal)?,
         None => 0,
     };
 
+    let (start, size) = if response.status() == StatusCode::PARTIAL_CONTENT {
+        // Content-Range is formatted as "bytes start-end/total"
+        let range = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.to_string())
+            .ok_or_else(|| {
+                RequestError::fatal("Partial response is missing a content range".to_string())
+            })?;
+
+        let start: u64 = range
+            .trim_start_matches("bytes ")
+            .split('-')
+            .next()
+            .and_then(|x| x.trim().parse().ok())
+            .ok_or_else(|| {
+                RequestError::fatal(format!("Failed to parse content range: {:?}", range))
+            })?;
+
+        let size = match range.rsplit('/').next().and_then(|x| x.parse().ok()) {
+            Some(v) => v,
+            None if length > 0 => start + length,
+            None => 0,
+        };
+
+        (start, size)
+    } else {
+        (0, length)
+    };
+
+    stream_reader(response, start, size, callback).map_err(RequestError::transient)
+}
+
+/// Feeds the contents of a reader to a callback in chunks, starting at the specified
+/// offset within the file.
+fn stream_reader<R, F>(
+    mut reader: R,
+    offset: u64,
+    size: u64,
+    callback: &mut F,
+) -> Result<(), String>
+where
+    R: Read,
+    F: FnMut(Vec<u8>, u64, u64) -> Result<(), String>,
+{
+    let mut position = offset;
+
     let mut buf = [0 as u8; 8192];
     loop {
-        let len = client
+        let len = reader
             .read(&mut buf)
             .map_err(|x| format!("Failed to read resource: {:?}", x))?;
 
@@ -74,7 +482,9 @@ where
         let buf_copy = &buf[0..len];
         let buf_copy = buf_copy.to_vec();
 
-        callback(buf_copy, size);
+        callback(buf_copy, position, size)?;
+
+        position += len as u64;
     }
 
     Ok(())
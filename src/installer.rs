@@ -7,6 +7,8 @@ use serde_json;
 use std::fs::File;
 use std::fs::OpenOptions;
 
+use std::collections::HashMap;
+
 use std::env;
 use std::env::var;
 
@@ -22,20 +24,28 @@ use std::process::exit;
 use std::process::Command;
 
 use config::BaseAttributes;
+use config::default_download_cache_size;
 use config::Config;
+use config::PackageDescription;
+
+use cache::DownloadCache;
 
 use sources::types::Version;
 
 use tasks::install::InstallTask;
+use tasks::resolver::list_releases;
 use tasks::uninstall::UninstallTask;
 use tasks::uninstall_global_shortcut::UninstallGlobalShortcutsTask;
 use tasks::DependencyTree;
 use tasks::TaskMessage;
+use tasks::TaskParamType;
 
 use logging::LoggingErrors;
 
+use dirs::cache_dir;
 use dirs::home_dir;
 
+use std::fs::remove_dir_all;
 use std::fs::remove_file;
 
 use http;
@@ -56,6 +66,10 @@ pub enum InstallMessage {
 pub struct InstallationDatabase {
     pub packages: Vec<LocalInstallation>,
     pub shortcuts: Vec<String>,
+    /// Last measured download speed (in bytes/sec) of each mirror's origin. Failed
+    /// mirrors are recorded as 0.
+    #[serde(default)]
+    pub mirror_speeds: HashMap<String, f64>,
 }
 
 impl InstallationDatabase {
@@ -64,10 +78,140 @@ impl InstallationDatabase {
         InstallationDatabase {
             packages: Vec::new(),
             shortcuts: Vec::new(),
+            mirror_speeds: HashMap::new(),
+        }
+    }
+}
+
+/// Choices made by the user about a individual package for a single install request.
+#[derive(Debug, Default, Clone)]
+pub struct PackageSelection {
+    /// The release channel to install from
+    pub channel: Option<String>,
+    /// An exact version to install, pinning the package to it
+    pub version: Option<String>,
+    /// If any existing pin should be removed, returning to the latest version
+    pub unpin: bool,
+}
+
+impl PackageSelection {
+    /// Works out the version requirement a package should be pinned to after this
+    /// selection is applied, given the current pin.
+    pub fn pin(&self, current: Option<&str>) -> Option<String> {
+        if let Some(ref version) = self.version {
+            return Some(format!("={}", version));
+        }
+
+        if self.unpin {
+            return None;
         }
+
+        current.map(|x| x.to_string())
     }
 }
 
+/// A package which an install request will add or remove, and why.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedPackage {
+    pub name: String,
+    pub reason: String,
+}
+
+/// What an install request will do once requirements and conflicts between packages
+/// have been resolved. Packages are listed in the order they are installed.
+#[derive(Serialize, Debug, Default)]
+pub struct InstallPlan {
+    pub install: Vec<PlannedPackage>,
+    pub uninstall: Vec<PlannedPackage>,
+    /// Problems which prevent the request from being carried out
+    pub errors: Vec<String>,
+}
+
+impl InstallPlan {
+    /// Returns true if the named package will be installed.
+    pub fn installs(&self, name: &str) -> bool {
+        self.install.iter().any(|x| x.name == name)
+    }
+
+    /// Adds a package to the plan after everything it requires.
+    fn add(
+        &mut self,
+        packages: &[PackageDescription],
+        name: &str,
+        reason: String,
+        visiting: &mut Vec<String>,
+    ) {
+        // Cycles are fine, as every package in one ends up installed anyway
+        if self.installs(name) || visiting.iter().any(|x| x == name) {
+            return;
+        }
+
+        let package = match packages.iter().find(|x| x.name == name) {
+            Some(v) => v,
+            None => {
+                self.errors.push(format!("Package {:?} could not be found.", name));
+                return;
+            }
+        };
+
+        visiting.push(name.to_string());
+
+        for requirement in &package.requires {
+            if packages.iter().any(|x| &x.name == requirement) {
+                self.add(packages, requirement, format!("Required by {}", name), visiting);
+            } else {
+                self.errors.push(format!(
+                    "{:?} requires {:?}, which doesn't exist.",
+                    name, requirement
+                ));
+            }
+        }
+
+        visiting.pop();
+
+        self.install.push(PlannedPackage {
+            name: name.to_string(),
+            reason,
+        });
+    }
+
+    /// Records an error for each pair of planned packages which conflict, in either
+    /// direction.
+    fn check_conflicts(&mut self, packages: &[PackageDescription]) {
+        for (i, first) in self.install.iter().enumerate() {
+            for second in &self.install[i + 1..] {
+                let conflicts = |a: &PlannedPackage, b: &PlannedPackage| {
+                    packages
+                        .iter()
+                        .find(|x| x.name == a.name)
+                        .map(|x| x.conflicts.contains(&b.name))
+                        .unwrap_or(false)
+                };
+
+                if conflicts(first, second) || conflicts(second, first) {
+                    self.errors.push(format!(
+                        "{:?} ({}) conflicts with {:?} ({}).",
+                        first.name,
+                        first.reason.to_lowercase(),
+                        second.name,
+                        second.reason.to_lowercase()
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// A release which can be installed for a package, as shown to the user.
+#[derive(Serialize)]
+pub struct AvailableRelease {
+    pub version: String,
+    pub tag: Option<String>,
+    pub name: Option<String>,
+    pub channel: Option<String>,
+    pub prerelease: bool,
+}
+
 /// The installer framework contains metadata about packages, what is installable, what isn't,
 /// etc.
 pub struct InstallerFramework {
@@ -80,6 +224,10 @@ pub struct InstallerFramework {
     // If we just completed an uninstall, and we should clean up after ourselves.
     pub burn_after_exit: bool,
     pub launcher_path: Option<String>,
+    /// Per-package choices for the install currently in progress
+    pub selections: HashMap<String, PackageSelection>,
+    /// Downloads which have already completed for the install in progress
+    pub prefetched: HashMap<String, TaskParamType>,
 }
 
 /// Contains basic properties on the status of the session. Subset of InstallationFramework.
@@ -97,10 +245,25 @@ pub struct InstallationStatus {
 pub struct LocalInstallation {
     pub name: String,
     pub version: Version,
+    /// The tag of the installed release, if the source provided one
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// The title of the installed release, if the source provided one
+    #[serde(default)]
+    pub release_name: Option<String>,
+    /// The release channel this package follows
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// A version requirement chosen by the user, preventing automatic updates
+    #[serde(default)]
+    pub pinned: Option<String>,
     /// Relative paths to generated files
     pub files: Vec<String>,
     /// Absolute paths to generated shortcut files
     pub shortcuts: Vec<String>,
+    /// SHA-256 digests of config files as they were installed, keyed by relative path
+    #[serde(default)]
+    pub file_hashes: HashMap<String, String>,
 }
 
 macro_rules! declare_messenger_callback {
@@ -127,6 +290,23 @@ impl InstallerFramework {
         self.config.clone()
     }
 
+    /// Creates a copy of this framework for use by another thread. Work done on the
+    /// copy is not reflected here.
+    pub fn fork(&self) -> InstallerFramework {
+        InstallerFramework {
+            base_attributes: self.base_attributes.clone(),
+            config: self.config.clone(),
+            database: self.database.clone(),
+            install_path: self.install_path.clone(),
+            preexisting_install: self.preexisting_install,
+            is_launcher: self.is_launcher,
+            burn_after_exit: self.burn_after_exit,
+            launcher_path: self.launcher_path.clone(),
+            selections: self.selections.clone(),
+            prefetched: HashMap::new(),
+        }
+    }
+
     /// Returns the default install path.
     pub fn get_default_path(&self) -> Option<String> {
         let app_name = &self.base_attributes.name;
@@ -147,13 +327,70 @@ impl InstallerFramework {
         Some(file.to_str()?.to_owned())
     }
 
+    /// Returns the directory used to cache data between runs, if an install
+    /// directory is known.
+    pub fn get_cache_dir(&self) -> Option<PathBuf> {
+        self.install_path.as_ref().map(|x| x.join(".cache"))
+    }
+
+    /// Returns the directory where the files of a package are laid out before they are
+    /// moved into the install directory.
+    pub fn get_staging_dir(&self, name: &str) -> Option<PathBuf> {
+        let dir_name = name.replace(|c: char| c == '/' || c == '\\', "_");
+
+        self.get_cache_dir()
+            .map(|x| x.join("staging").join(dir_name))
+    }
+
+    /// Returns the cache of downloaded packages, which is shared between installs.
+    pub fn get_download_cache(&self) -> DownloadCache {
+        let base_dir = cache_dir().unwrap_or_else(env::temp_dir);
+
+        let size = self
+            .config
+            .as_ref()
+            .map(|x| x.download_cache_size)
+            .unwrap_or_else(default_download_cache_size);
+
+        DownloadCache::new(
+            base_dir.join(&self.base_attributes.name).join("downloads"),
+            size * 1024 * 1024,
+        )
+    }
+
+    /// Lists all releases of a package which could be installed, newest first.
+    pub fn get_available_releases(&self, name: &str) -> Result<Vec<AvailableRelease>, String> {
+        let package = self
+            .config
+            .as_ref()
+            .ok_or_else(|| "Config has not been loaded".to_string())?
+            .packages
+            .iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| format!("Package {:?} could not be found.", name))?;
+
+        let releases = list_releases(self, package, None)?;
+
+        Ok(releases
+            .into_iter()
+            .map(|(release, _)| AvailableRelease {
+                version: release.version.to_string(),
+                tag: release.tag,
+                name: release.name,
+                channel: release.channel,
+                prerelease: release.prerelease,
+            }).collect())
+    }
+
     /// Sends a request for something to be installed.
     /// items: Array of named packages to be installed/kept
+    /// selections: Per-package choices, such as which channel to follow
     /// messages: Channel used to send progress messages
     /// fresh_install: If the install directory must be empty
     pub fn install(
         &mut self,
         items: Vec<String>,
+        selections: HashMap<String, PackageSelection>,
         messages: &Sender<InstallMessage>,
         fresh_install: bool,
     ) -> Result<(), String> {
@@ -165,21 +402,27 @@ impl InstallerFramework {
                 .log_expect("Install directory not initialised")
         );
 
-        // Calculate packages to *uninstall*
-        let mut uninstall_items = Vec::new();
-        if !fresh_install {
-            for package in &self.database.packages {
-                if !items.contains(&package.name) {
-                    uninstall_items.push(package.name.clone());
-                }
-            }
+        let plan = self.plan_install(&items, fresh_install);
+
+        if !plan.errors.is_empty() {
+            return Err(plan.errors.join("\n"));
+        }
+
+        let items: Vec<String> = plan.install.into_iter().map(|x| x.name).collect();
+        let uninstall_items: Vec<String> = plan.uninstall.into_iter().map(|x| x.name).collect();
+
+        info!("Framework: Installing {:?} after resolving requirements.", items);
 
+        if !uninstall_items.is_empty() {
             info!(
                 "Framework: Uninstalling {:?} additionally.",
                 uninstall_items
             );
         }
 
+        self.selections = selections;
+        self.prefetched.clear();
+
         let task = Box::new(InstallTask {
             items,
             uninstall_items,
@@ -190,8 +433,43 @@ impl InstallerFramework {
 
         info!("Dependency tree:\n{}", tree);
 
-        tree.execute(self, declare_messenger_callback!(messages))
-            .map(|_x| ())
+        let result = tree.execute(self, declare_messenger_callback!(messages));
+
+        // Downloads left over from a failed install may not match the next request
+        self.prefetched.clear();
+
+        result.map(|_x| ())
+    }
+
+    /// Works out which packages an install request adds and removes, pulling in the
+    /// packages they require and checking for conflicts between them.
+    pub fn plan_install(&self, items: &[String], fresh_install: bool) -> InstallPlan {
+        let packages = &self
+            .config
+            .as_ref()
+            .log_expect("Should have packages by now")
+            .packages;
+
+        let mut plan = InstallPlan::default();
+
+        for item in items {
+            plan.add(packages, item, "Selected".to_string(), &mut Vec::new());
+        }
+
+        plan.check_conflicts(packages);
+
+        if !fresh_install {
+            for package in &self.database.packages {
+                if !plan.installs(&package.name) {
+                    plan.uninstall.push(PlannedPackage {
+                        name: package.name.clone(),
+                        reason: "Not selected".to_string(),
+                    });
+                }
+            }
+        }
+
+        plan
     }
 
     /// Sends a request for everything to be uninstalled.
@@ -203,14 +481,19 @@ impl InstallerFramework {
             .map(|x| x.name.clone())
             .collect();
 
+        self.prefetched.clear();
+
         let task = Box::new(UninstallTask { items });
 
         let mut tree = DependencyTree::build(task);
 
         info!("Dependency tree:\n{}", tree);
 
-        tree.execute(self, declare_messenger_callback!(messages))
-            .map(|_x| ())?;
+        let result = tree.execute(self, declare_messenger_callback!(messages));
+
+        self.prefetched.clear();
+
+        result.map(|_x| ())?;
 
         // Uninstall shortcuts
         let task = Box::new(UninstallGlobalShortcutsTask {});
@@ -229,6 +512,13 @@ impl InstallerFramework {
         remove_file(path.join("metadata.json"))
             .map_err(|x| format!("Failed to delete metadata: {:?}", x))?;
 
+        if let Some(cache_dir) = self.get_cache_dir() {
+            if cache_dir.exists() {
+                remove_dir_all(cache_dir)
+                    .map_err(|x| format!("Failed to delete cache: {:?}", x))?;
+            }
+        }
+
         // Logging will have to be done later
         self.burn_after_exit = true;
 
@@ -404,6 +694,8 @@ impl InstallerFramework {
             is_launcher: false,
             burn_after_exit: false,
             launcher_path: None,
+            selections: HashMap::new(),
+            prefetched: HashMap::new(),
         }
     }
 
@@ -431,6 +723,181 @@ impl InstallerFramework {
             is_launcher: false,
             burn_after_exit: false,
             launcher_path: None,
+            selections: HashMap::new(),
+            prefetched: HashMap::new(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toml;
+
+    fn package(name: &str, requires: &[&str], conflicts: &[&str]) -> PackageDescription {
+        toml::from_str(&format!(
+            "name = {:?}\n\
+             description = \"\"\n\
+             requires = {:?}\n\
+             conflicts = {:?}\n\
+             [source]\n\
+             name = \"github\"\n\
+             match = \".*\"\n\
+             config = {{ repo = \"a/b\" }}\n",
+            name, requires, conflicts
+        ))
+        .unwrap()
+    }
+
+    /// Builds an installer offering the specified packages.
+    fn framework(packages: &[PackageDescription]) -> InstallerFramework {
+        let attributes = BaseAttributes::from_toml_str(
+            "name = \"test\"\n\
+             target_url = \"\"\n",
+        )
+        .unwrap();
+
+        let mut config = Config::from_toml_str(
+            "installing_message = \"\"\n\
+             packages = []\n",
+        )
+        .unwrap();
+        config.packages = packages.to_vec();
+
+        let mut framework = InstallerFramework::new(attributes);
+        framework.config = Some(config);
+        framework
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// Plans a fresh install of the selected packages.
+    fn plan(packages: &[PackageDescription], items: &[&str]) -> InstallPlan {
+        framework(packages).plan_install(&strings(items), true)
+    }
+
+    fn names(plan: &InstallPlan) -> Vec<&str> {
+        plan.install.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn add_installs_requirements_first() {
+        let packages = vec![
+            package("a", &["b"], &[]),
+            package("b", &["c"], &[]),
+            package("c", &[], &[]),
+        ];
+
+        let plan = plan(&packages, &["a"]);
+
+        assert_eq!(names(&plan), vec!["c", "b", "a"]);
+        assert_eq!(plan.install[0].reason, "Required by b");
+        assert_eq!(plan.install[2].reason, "Selected");
+        assert!(plan.errors.is_empty());
+    }
+
+    #[test]
+    fn add_lists_shared_requirements_once() {
+        let packages = vec![
+            package("a", &["c"], &[]),
+            package("b", &["c"], &[]),
+            package("c", &[], &[]),
+        ];
+
+        let plan = plan(&packages, &["a", "b", "c"]);
+
+        assert_eq!(names(&plan), vec!["c", "a", "b"]);
+        assert_eq!(plan.install[0].reason, "Required by a");
+    }
+
+    #[test]
+    fn add_allows_cycles() {
+        let packages = vec![
+            package("a", &["b"], &[]),
+            package("b", &["a"], &[]),
+            package("c", &["c"], &[]),
+        ];
+
+        let plan = plan(&packages, &["a", "c"]);
+
+        assert_eq!(names(&plan), vec!["b", "a", "c"]);
+        assert!(plan.errors.is_empty());
+    }
+
+    #[test]
+    fn add_reports_missing_packages() {
+        let packages = vec![package("a", &["missing"], &[])];
+
+        let plan = plan(&packages, &["a", "unknown"]);
+
+        assert_eq!(names(&plan), vec!["a"]);
+        assert_eq!(plan.errors.len(), 2);
+        assert!(plan.errors[0].contains("\"missing\""));
+        assert!(plan.errors[1].contains("\"unknown\""));
+    }
+
+    #[test]
+    fn check_conflicts_in_either_direction() {
+        let packages = vec![
+            package("a", &[], &["b"]),
+            package("b", &[], &[]),
+            package("c", &[], &[]),
+        ];
+
+        assert_eq!(plan(&packages, &["a", "b"]).errors.len(), 1);
+        assert_eq!(plan(&packages, &["b", "a"]).errors.len(), 1);
+        assert!(plan(&packages, &["a", "c"]).errors.is_empty());
+    }
+
+    #[test]
+    fn check_conflicts_with_requirements() {
+        let packages = vec![
+            package("a", &["b"], &[]),
+            package("b", &[], &[]),
+            package("c", &[], &["b"]),
+        ];
+
+        let plan = plan(&packages, &["a", "c"]);
+
+        assert_eq!(plan.errors.len(), 1);
+        assert!(plan.errors[0].contains("required by a"));
+    }
+
+    #[test]
+    fn plan_install_uninstalls_unselected_packages() {
+        let packages = vec![
+            package("a", &["b"], &[]),
+            package("b", &[], &[]),
+            package("c", &[], &[]),
+        ];
+
+        let mut framework = framework(&packages);
+        for name in &["a", "b", "c"] {
+            framework.database.packages.push(LocalInstallation {
+                name: name.to_string(),
+                version: Version::new_number(1),
+                tag: None,
+                release_name: None,
+                channel: None,
+                pinned: None,
+                files: Vec::new(),
+                shortcuts: Vec::new(),
+                file_hashes: HashMap::new(),
+            });
+        }
+
+        let plan = framework.plan_install(&strings(&["a"]), false);
+
+        assert_eq!(names(&plan), vec!["b", "a"]);
+        assert_eq!(plan.uninstall.len(), 1);
+        assert_eq!(plan.uninstall[0].name, "c");
+        assert_eq!(plan.uninstall[0].reason, "Not selected");
+
+        // Nothing is installed yet to remove
+        let plan = framework.plan_install(&strings(&["a"]), true);
+        assert!(plan.uninstall.is_empty());
+    }
+}
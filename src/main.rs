@@ -27,11 +27,20 @@ extern crate toml;
 
 extern crate regex;
 extern crate semver;
+extern crate minisign_verify;
+extern crate sha2;
 
 extern crate dirs;
+#[macro_use]
+extern crate lazy_static;
+extern crate bzip2;
+extern crate filetime;
+extern crate flate2;
+extern crate glob;
 extern crate tar;
-extern crate xz_decom;
+extern crate xz2;
 extern crate zip;
+extern crate zstd;
 
 extern crate fern;
 #[macro_use]
@@ -45,12 +54,16 @@ extern crate winapi;
 
 mod archives;
 mod assets;
+mod cache;
+mod checksum;
 mod config;
+mod delta;
 mod http;
 mod installer;
 mod logging;
 mod native;
 mod rest;
+mod signing;
 mod sources;
 mod tasks;
 
@@ -100,6 +113,8 @@ fn main() {
     logging::setup_logger(format!("{}_installer.log", config.name))
         .expect("Unable to setup logging!");
 
+    http::configure(config.network.clone());
+
     let app_name = config.name.clone();
 
     let app_about = format!("An interactive installer for {}", app_name);
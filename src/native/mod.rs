@@ -20,6 +20,9 @@ mod natives {
     use logging::LoggingErrors;
 
     use std::env;
+    use std::fs::copy;
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    use std::path::Path;
     use std::process::Command;
 
     use winapi::shared::minwindef::{DWORD, FALSE, MAX_PATH};
@@ -42,6 +45,30 @@ mod natives {
         ) -> ::std::os::raw::c_int;
     }
 
+    /// Creates a symbolic link. Creating these requires extra privileges on Windows, so
+    /// links to files are copied instead if this isn't possible.
+    pub fn create_symlink(target: &Path, link: &Path) -> Result<(), String> {
+        let resolved = link.parent().map(|x| x.join(target)).unwrap_or_else(|| target.into());
+
+        let result = if resolved.is_dir() {
+            symlink_dir(target, link)
+        } else {
+            symlink_file(target, link)
+        };
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(v) if resolved.is_file() => {
+                warn!("Unable to create symlink {:?} ({:?}), copying instead", link, v);
+
+                copy(&resolved, link)
+                    .map(|_| ())
+                    .map_err(|x| format!("Unable to copy {:?}: {:?}", resolved, x))
+            }
+            Err(v) => Err(format!("Unable to create symlink {:?}: {:?}", link, v)),
+        }
+    }
+
     // Needed here for Windows interop
     #[allow(unsafe_code)]
     pub fn create_shortcut(
@@ -204,6 +231,8 @@ mod natives {
 #[cfg(not(windows))]
 mod natives {
     use std::fs::remove_file;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
 
     use std::env;
 
@@ -222,6 +251,11 @@ mod natives {
         Ok("".to_string())
     }
 
+    /// Creates a symbolic link.
+    pub fn create_symlink(target: &Path, link: &Path) -> Result<(), String> {
+        symlink(target, link).map_err(|x| format!("Unable to create symlink {:?}: {:?}", link, x))
+    }
+
     /// Cleans up the installer
     pub fn burn_on_exit(app_name: &str) {
         let current_exe = env::current_exe().log_expect("Current executable could not be found");
@@ -30,11 +30,14 @@ use assets;
 
 use installer::InstallMessage;
 use installer::InstallerFramework;
+use installer::PackageSelection;
 
 use logging::LoggingErrors;
 
 use http;
 
+use signing::{Verifier, SIGNATURE_EXTENSION};
+
 use config::Config;
 
 use native;
@@ -117,10 +120,19 @@ impl Service for WebService {
                     framework.base_attributes.target_url
                 );
 
-                match http::download_text(&framework.base_attributes.target_url)
-                    .map(|x| Config::from_toml_str(&x))
+                let target_url = framework.base_attributes.target_url.clone();
+
+                match Verifier::new(&framework.base_attributes)
+                    .and_then(|verifier| {
+                        let text = http::download_text(&target_url)?;
+                        let signature_url = format!("{}{}", target_url, SIGNATURE_EXTENSION);
+                        verifier.verify_data("config", text.as_bytes(), &signature_url)?;
+                        Ok(text)
+                    }).map(|x| Config::from_toml_str(&x))
                 {
                     Ok(Ok(config)) => {
+                        http::configure(framework.base_attributes.network.merge(&config.network));
+
                         framework.config = Some(config.clone());
 
                         info!("Configuration file downloaded successfully.");
@@ -145,10 +157,7 @@ impl Service for WebService {
                             .with_body("Bad HTTP response")
                     }
                     Err(v) => {
-                        error!(
-                            "General connectivity error while downloading config: {:?}",
-                            v
-                        );
+                        error!("Error while downloading config: {:?}", v);
 
                         Response::<hyper::Body>::new()
                             .with_status(StatusCode::ServiceUnavailable)
@@ -232,6 +241,124 @@ impl Service for WebService {
                     .with_header(ContentType::json())
                     .with_body(file)
             }
+            // Describes the contents of the shared download cache
+            (&Get, "/api/cache") => {
+                let framework = self
+                    .framework
+                    .read()
+                    .log_expect("InstallerFramework has been dirtied");
+
+                let response = framework.get_download_cache().status();
+
+                let file = serde_json::to_string(&response)
+                    .log_expect("Failed to render JSON payload of cache status");
+
+                Response::<hyper::Body>::new()
+                    .with_header(ContentLength(file.len() as u64))
+                    .with_header(ContentType::json())
+                    .with_body(file)
+            }
+            // Removes everything from the shared download cache
+            (&Post, "/api/clear-cache") => {
+                let framework = self
+                    .framework
+                    .read()
+                    .log_expect("InstallerFramework has been dirtied");
+
+                let cache = framework.get_download_cache();
+
+                match cache.clear() {
+                    Ok(_) => {
+                        let file = serde_json::to_string(&cache.status())
+                            .log_expect("Failed to render JSON payload of cache status");
+
+                        Response::<hyper::Body>::new()
+                            .with_header(ContentLength(file.len() as u64))
+                            .with_header(ContentType::json())
+                            .with_body(file)
+                    }
+                    Err(v) => {
+                        error!("Failed to clear download cache: {:?}", v);
+
+                        Response::<hyper::Body>::new()
+                            .with_status(StatusCode::InternalServerError)
+                            .with_header(ContentLength(v.len() as u64))
+                            .with_header(ContentType::plaintext())
+                            .with_body(v)
+                    }
+                }
+            }
+            // Lists the releases available for a package
+            (&Post, "/api/releases") => {
+                let framework = self.framework.clone();
+
+                return Box::new(req.body().concat2().map(move |b| {
+                    let results = form_urlencoded::parse(b.as_ref())
+                        .into_owned()
+                        .collect::<HashMap<String, String>>();
+
+                    let framework = framework
+                        .read()
+                        .log_expect("InstallerFramework has been dirtied");
+
+                    let releases = match results.get("package") {
+                        Some(name) => framework.get_available_releases(name),
+                        None => Err("No package specified".to_string()),
+                    };
+
+                    match releases {
+                        Ok(releases) => {
+                            let file = serde_json::to_string(&releases)
+                                .log_expect("Failed to render JSON payload of releases");
+
+                            Response::<hyper::Body>::new()
+                                .with_header(ContentLength(file.len() as u64))
+                                .with_header(ContentType::json())
+                                .with_body(file)
+                        }
+                        Err(v) => {
+                            error!("Failed to list releases: {:?}", v);
+
+                            Response::<hyper::Body>::new()
+                                .with_status(StatusCode::ServiceUnavailable)
+                                .with_header(ContentLength(v.len() as u64))
+                                .with_header(ContentType::plaintext())
+                                .with_body(v)
+                        }
+                    }
+                }));
+            }
+            // Resolves what installing a set of packages would do, before it starts
+            (&Post, "/api/plan") => {
+                let framework = self.framework.clone();
+
+                return Box::new(req.body().concat2().map(move |b| {
+                    let results = form_urlencoded::parse(b.as_ref())
+                        .into_owned()
+                        .collect::<HashMap<String, String>>();
+
+                    // Only package checkboxes are sent as booleans
+                    let items = results
+                        .iter()
+                        .filter(|&(_, value)| value == "true")
+                        .map(|(key, _)| key.to_owned())
+                        .collect::<Vec<_>>();
+
+                    let framework = framework
+                        .read()
+                        .log_expect("InstallerFramework has been dirtied");
+
+                    let plan = framework.plan_install(&items, !framework.preexisting_install);
+
+                    let file = serde_json::to_string(&plan)
+                        .log_expect("Failed to render JSON payload of install plan");
+
+                    Response::<hyper::Body>::new()
+                        .with_header(ContentLength(file.len() as u64))
+                        .with_header(ContentType::json())
+                        .with_body(file)
+                }));
+            }
             // Streams the installation of a particular set of packages
             (&Post, "/api/uninstall") => {
                 // We need to bit of pipelining to get this to work
@@ -353,6 +480,7 @@ impl Service for WebService {
                         .collect::<HashMap<String, String>>();
 
                     let mut to_install = Vec::new();
+                    let mut selections: HashMap<String, PackageSelection> = HashMap::new();
                     let mut path: Option<String> = None;
 
                     // Transform results into just an array of stuff to install
@@ -362,6 +490,27 @@ impl Service for WebService {
                             continue;
                         }
 
+                        if key.starts_with("version:") {
+                            let selection = selections
+                                .entry(key["version:".len()..].to_owned())
+                                .or_insert_with(PackageSelection::default);
+
+                            if value == "latest" {
+                                selection.unpin = true;
+                            } else {
+                                selection.version = Some(value.to_owned());
+                            }
+                            continue;
+                        }
+
+                        if key.starts_with("channel:") {
+                            selections
+                                .entry(key["channel:".len()..].to_owned())
+                                .or_insert_with(PackageSelection::default)
+                                .channel = Some(value.to_owned());
+                            continue;
+                        }
+
                         if value == "true" {
                             to_install.push(key.to_owned());
                         }
@@ -386,7 +535,9 @@ impl Service for WebService {
                             framework.set_install_dir(&path);
                         }
 
-                        if let Err(v) = framework.install(to_install, &sender, new_install) {
+                        if let Err(v) =
+                            framework.install(to_install, selections, &sender, new_install)
+                        {
                             error!("Install error occurred: {:?}", v);
                             if let Err(v) = sender.send(InstallMessage::Error(v)) {
                                 error!("Failed to send install error: {:?}", v);
@@ -0,0 +1,133 @@
+//! signing.rs
+//!
+//! Verifies detached minisign (ed25519) signatures against the public key embedded in
+//! the bootstrap config.
+
+use minisign_verify::{Error as MinisignError, PublicKey, Signature};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use config::BaseAttributes;
+
+use http::download_text;
+
+/// The file extension used for detached signatures.
+pub const SIGNATURE_EXTENSION: &'static str = ".minisig";
+
+/// Describes how content should be checked before it is trusted.
+pub struct Verifier {
+    key: Option<PublicKey>,
+    allow_unsigned: bool,
+}
+
+impl Verifier {
+    /// Builds a verifier from the bootstrap config of this installer.
+    pub fn new(attributes: &BaseAttributes) -> Result<Self, String> {
+        let key = match attributes.public_key {
+            Some(ref key) => Some(
+                PublicKey::from_base64(key.trim())
+                    .map_err(|x| format!("Embedded public key is invalid: {:?}", x))?,
+            ),
+            None => None,
+        };
+
+        if key.is_none() && !attributes.allow_unsigned {
+            return Err("No public key is embedded in this installer".to_string());
+        }
+
+        Ok(Verifier {
+            key,
+            allow_unsigned: attributes.allow_unsigned,
+        })
+    }
+
+    /// Downloads the detached signature stored at the specified URL. Missing signatures
+    /// are only tolerated if this build opts out of requiring them.
+    fn fetch_signature(&self, name: &str, url: &str) -> Result<Option<Signature>, String> {
+        if self.key.is_none() {
+            return Ok(None);
+        }
+
+        let text = match download_text(url) {
+            Ok(v) => v,
+            Err(v) => {
+                if self.allow_unsigned {
+                    warn!("No signature found for {:?}, continuing anyway: {}", name, v);
+                    return Ok(None);
+                }
+
+                return Err(format!("Unable to fetch signature for {:?}: {}", name, v));
+            }
+        };
+
+        Signature::decode(&text)
+            .map(Some)
+            .map_err(|x| format!("Signature for {:?} is malformed: {:?}", name, x))
+    }
+
+    /// Verifies an in-memory blob against the signature stored at the specified URL.
+    pub fn verify_data(&self, name: &str, data: &[u8], signature_url: &str) -> Result<(), String> {
+        let signature = match self.fetch_signature(name, signature_url)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let key = self.key.as_ref().ok_or("No public key available")?;
+
+        key.verify(data, &signature, true)
+            .map_err(|x| format!("Signature verification of {:?} failed: {:?}", name, x))?;
+
+        info!("Verified signature of {:?}", name);
+
+        Ok(())
+    }
+
+    /// Verifies a file on disk against the signature stored at the specified URL.
+    pub fn verify_file(&self, name: &str, path: &Path, signature_url: &str) -> Result<(), String> {
+        let signature = match self.fetch_signature(name, signature_url)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let key = self.key.as_ref().ok_or("No public key available")?;
+
+        let mut file =
+            File::open(path).map_err(|x| format!("Failed to open {:?}: {:?}", path, x))?;
+
+        let result = match key.verify_stream(&signature) {
+            Ok(mut verifier) => {
+                let mut buf = [0u8; 8192];
+                loop {
+                    let len = file
+                        .read(&mut buf)
+                        .map_err(|x| format!("Failed to read {:?}: {:?}", path, x))?;
+
+                    if len == 0 {
+                        break;
+                    }
+
+                    verifier.update(&buf[0..len]);
+                }
+
+                verifier.finalize()
+            }
+            // Legacy signatures sign the file directly, so it must be read into memory
+            Err(MinisignError::UnsupportedLegacyMode) => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)
+                    .map_err(|x| format!("Failed to read {:?}: {:?}", path, x))?;
+
+                key.verify(&data, &signature, true)
+            }
+            Err(v) => Err(v),
+        };
+
+        result.map_err(|x| format!("Signature verification of {:?} failed: {:?}", name, x))?;
+
+        info!("Verified signature of {:?}", name);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! embedded/mod.rs
+//!
+//! Contains a release source which serves package archives bundled into the installer
+//! at build time (see `embedded_packages` in the bootstrap config).
+
+use std::path::Path;
+
+use sources::types::*;
+
+/// The URL scheme used to refer to embedded payloads.
+pub const URL_PREFIX: &'static str = "embedded://";
+
+/// File names and contents of all embedded payloads, generated by build.rs.
+static PAYLOADS: &'static [(&'static str, &'static [u8])] =
+    include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+/// Returns the contents of the named embedded payload, if it exists.
+pub fn get_payload(name: &str) -> Option<&'static [u8]> {
+    PAYLOADS
+        .iter()
+        .find(|&&(payload_name, _)| payload_name == name)
+        .map(|&(_, data)| data)
+}
+
+pub struct EmbeddedReleases {}
+
+/// The configuration for this release.
+#[derive(Serialize, Deserialize)]
+struct EmbeddedConfig {
+    /// The version of the bundled payloads.
+    version: String,
+}
+
+impl EmbeddedReleases {
+    pub fn new() -> Self {
+        EmbeddedReleases {}
+    }
+}
+
+impl ReleaseSource for EmbeddedReleases {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        _cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
+        // Reparse our Config as strongly typed
+        let config: EmbeddedConfig = match config.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
+        };
+
+        let version = match Version::parse(&config.version) {
+            Some(v) => v,
+            None => return Err(format!("Unparsable version: {:?}", config.version)),
+        };
+
+        let files = PAYLOADS
+            .iter()
+            .map(|&(name, data)| File {
+                name: name.to_string(),
+                url: format!("{}{}", URL_PREFIX, name),
+                size: Some(data.len() as u64),
+                sha256: None,
+                mirrors: Vec::new(),
+            }).collect();
+
+        Ok(vec![Release {
+            version,
+            files,
+            tag: None,
+            name: None,
+            prerelease: false,
+            channel: None,
+        }])
+    }
+}
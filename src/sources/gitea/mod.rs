@@ -0,0 +1,273 @@
+//! gitea/mod.rs
+//!
+//! Contains the Gitea API implementation of a release source.
+
+use reqwest::header::{AUTHORIZATION, LINK, USER_AGENT};
+use reqwest::StatusCode;
+
+use serde_json;
+
+use std::path::Path;
+
+use sources::next_page;
+use sources::types::*;
+
+use http::build_client;
+
+pub struct GiteaReleases {}
+
+/// The configuration for this release.
+#[derive(Serialize, Deserialize)]
+struct GiteaConfig {
+    repo: String,
+    base_url: String,
+    #[serde(default)]
+    token: Option<String>,
+    /// The maximum number of pages of releases to fetch.
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+}
+
+fn default_max_pages() -> u32 {
+    10
+}
+
+impl GiteaReleases {
+    pub fn new() -> Self {
+        GiteaReleases {}
+    }
+}
+
+impl ReleaseSource for GiteaReleases {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        _cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
+        // Reparse our Config as strongly typed
+        let config: GiteaConfig = match config.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
+        };
+
+        let mut results: Vec<Release> = Vec::new();
+
+        // Build the HTTP client up
+        let client = build_client()?;
+
+        let mut entries: Vec<serde_json::Value> = Vec::new();
+        let mut page = Some(1);
+
+        while let Some(current) = page.take() {
+            if current > config.max_pages {
+                warn!(
+                    "Stopped fetching releases for {:?} after {} pages",
+                    config.repo, config.max_pages
+                );
+                break;
+            }
+
+            // Servers cap the page size, by default at 50
+            let mut request = client
+                .get(&format!(
+                    "{}/api/v1/repos/{}/releases?limit=50&page={}",
+                    config.base_url.trim_end_matches('/'),
+                    config.repo,
+                    current
+                )).header(USER_AGENT, "liftinstall (j-selby)");
+
+            if let Some(ref token) = config.token {
+                request = request.header(AUTHORIZATION, format!("token {}", token).as_str());
+            }
+
+            let mut response = request
+                .send()
+                .map_err(|x| format!("Error while sending HTTP request: {:?}", x))?;
+
+            match response.status() {
+                StatusCode::OK => {}
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+                    return Err(format!(
+                        "Gitea refused access to {:?} ({:?}). Check the repository name and \
+                         token.",
+                        config.repo,
+                        response.status()
+                    ));
+                }
+                _ => {
+                    return Err(format!("Bad status code: {:?}.", response.status()));
+                }
+            }
+
+            let link = response
+                .headers()
+                .get(LINK)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string());
+
+            let body = response
+                .text()
+                .map_err(|x| format!("Failed to decode HTTP response body: {:?}", x))?;
+
+            let result: serde_json::Value = serde_json::from_str(&body)
+                .map_err(|x| format!("Failed to parse response: {:?}", x))?;
+
+            match result {
+                serde_json::Value::Array(v) => {
+                    page = next_page(link.as_ref().map(|x| x.as_str()), current, v.is_empty());
+                    entries.extend(v);
+                }
+                _ => return Err("Response was not an array!".to_string()),
+            }
+        }
+
+        // Release IDs can't be compared with semver versions, so only fall back to them
+        // if no tag could be parsed as one
+        let any_semver = entries.iter().any(|entry| {
+            match entry["tag_name"].as_str().and_then(Version::from_tag) {
+                Some(Version::Semver(_)) => true,
+                _ => false,
+            }
+        });
+
+        // Parse JSON from server
+        for entry in entries.iter() {
+            let mut files = Vec::new();
+
+            let id: u64 = match entry["id"].as_u64() {
+                Some(v) => v,
+                None => return Err("JSON payload missing information about ID".to_string()),
+            };
+
+            // Prefer the tag as a version, but fall back to the release ID
+            let version = match entry["tag_name"].as_str().and_then(Version::from_tag) {
+                Some(Version::Integer(_)) | None if any_semver => {
+                    warn!(
+                        "Skipping Gitea release with unparsable tag {:?}",
+                        entry["tag_name"]
+                    );
+                    continue;
+                }
+                Some(v) => v,
+                None => Version::new_number(id),
+            };
+
+            let assets = match entry["assets"].as_array() {
+                Some(v) => v,
+                None => return Err("JSON payload not an array".to_string()),
+            };
+
+            for asset in assets.iter() {
+                let string = match asset["name"].as_str() {
+                    Some(v) => v,
+                    None => {
+                        return Err("JSON payload missing information about release name".to_string())
+                    }
+                };
+
+                let url = match asset["browser_download_url"].as_str() {
+                    Some(v) => v,
+                    None => {
+                        return Err("JSON payload missing information about release URL".to_string())
+                    }
+                };
+
+                files.push(File {
+                    name: string.to_string(),
+                    url: url.to_string(),
+                    size: asset["size"].as_u64(),
+                    sha256: None,
+                    mirrors: Vec::new(),
+                });
+            }
+
+            results.push(Release {
+                version,
+                files,
+                tag: entry["tag_name"].as_str().map(|x| x.to_string()),
+                name: entry["name"].as_str().map(|x| x.to_string()),
+                prerelease: entry["prerelease"].as_bool().unwrap_or(false),
+                channel: None,
+            });
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sources::serve_http;
+
+    fn release(tag: &str) -> String {
+        format!("{{\"id\": 1, \"tag_name\": {:?}, \"assets\": []}}", tag)
+    }
+
+    fn versions(config: &str) -> Vec<String> {
+        let config: TomlValue = ::toml::from_str(config).unwrap();
+
+        GiteaReleases::new()
+            .get_current_releases(&config, None)
+            .unwrap()
+            .iter()
+            .map(|x| x.version.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn follows_link_headers() {
+        let (url, server) = serve_http(vec![
+            (
+                "Link: <http://next>; rel=\"next\"\r\n",
+                format!("[{}, {}]", release("v1.0.2"), release("v1.0.1")),
+            ),
+            (
+                "Link: <http://first>; rel=\"first\"\r\n",
+                format!("[{}]", release("v1.0.0")),
+            ),
+        ]);
+
+        assert_eq!(
+            versions(&format!("repo = \"owner/repo\"\nbase_url = {:?}", url)),
+            vec!["1.0.2", "1.0.1", "1.0.0"]
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "/api/v1/repos/owner/repo/releases?limit=50&page=1",
+                "/api/v1/repos/owner/repo/releases?limit=50&page=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn requests_pages_until_one_is_empty() {
+        let (url, server) = serve_http(vec![
+            ("", format!("[{}]", release("v1.0.1"))),
+            ("", format!("[{}]", release("v1.0.0"))),
+            ("", "[]".to_string()),
+        ]);
+
+        assert_eq!(
+            versions(&format!("repo = \"owner/repo\"\nbase_url = {:?}", url)),
+            vec!["1.0.1", "1.0.0"]
+        );
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn stops_after_max_pages() {
+        let (url, server) = serve_http(vec![("", format!("[{}]", release("v1.0.1")))]);
+
+        assert_eq!(
+            versions(&format!(
+                "{}\nmax_pages = 1",
+                format!("repo = \"owner/repo\"\nbase_url = {:?}", url)
+            )),
+            vec!["1.0.1"]
+        );
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
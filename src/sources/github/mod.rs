@@ -2,75 +2,319 @@
 //!
 //! Contains the Github API implementation of a release source.
 
-use reqwest::header::USER_AGENT;
+use reqwest::header::{AUTHORIZATION, ETAG, IF_NONE_MATCH, LINK, RETRY_AFTER, USER_AGENT};
+use reqwest::Client;
+use reqwest::Response;
 use reqwest::StatusCode;
 
 use serde_json;
 
+use regex::Regex;
+
+use std::env;
+use std::fs::create_dir_all;
+use std::fs::File as FsFile;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sources::parse_next_link;
 use sources::types::*;
 
 use http::build_client;
 
+/// How many times a rate limited request is retried before giving up.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
 pub struct GithubReleases {}
 
 /// The configuration for this release.
 #[derive(Serialize, Deserialize)]
 struct GithubConfig {
     repo: String,
+    /// A regex matched against the start of tag names and removed before they are
+    /// parsed as versions (e.g. "release-").
+    #[serde(default)]
+    tag_prefix: Option<String>,
+    /// An API token, used to raise the rate limit.
+    #[serde(default)]
+    token: Option<String>,
+    /// An environment variable to read an API token from, if none is configured.
+    #[serde(default = "default_token_env")]
+    token_env: String,
+    /// The maximum number of pages of releases to fetch.
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+    /// The longest time, in seconds, to wait for a rate limit to reset.
+    #[serde(default = "default_max_wait")]
+    max_rate_limit_wait: u64,
+}
+
+fn default_token_env() -> String {
+    "GITHUB_TOKEN".to_string()
+}
+
+fn default_max_pages() -> u32 {
+    10
+}
+
+fn default_max_wait() -> u64 {
+    60
+}
+
+/// A previous response from the API, reusable if the server reports it unchanged.
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    etag: String,
+    body: String,
+    next: Option<String>,
 }
 
 impl GithubReleases {
     pub fn new() -> Self {
         GithubReleases {}
     }
+
+    /// Fetches a single page of the API, returning the body and the URL of the next page.
+    fn fetch_page(
+        client: &Client,
+        url: &str,
+        token: Option<&str>,
+        cache_dir: Option<&Path>,
+        max_wait: u64,
+    ) -> Result<(String, Option<String>), String> {
+        let cache_file = cache_dir.map(|x| x.join(format!("{}.json", cache_key(url))));
+
+        let cached: Option<CachedResponse> = cache_file
+            .as_ref()
+            .and_then(|x| FsFile::open(x).ok())
+            .and_then(|x| serde_json::from_reader(x).ok());
+
+        let mut attempts = 0;
+
+        loop {
+            let mut request = client.get(url).header(USER_AGENT, "liftinstall (j-selby)");
+
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("token {}", token).as_str());
+            }
+
+            if let Some(ref cached) = cached {
+                request = request.header(IF_NONE_MATCH, cached.etag.as_str());
+            }
+
+            let mut response = request
+                .send()
+                .map_err(|x| format!("Error while sending HTTP request: {:?}", x))?;
+
+            match response.status() {
+                StatusCode::OK => {}
+                StatusCode::NOT_MODIFIED if cached.is_some() => {
+                    info!("GitHub reports {:?} unchanged, using cached copy", url);
+                    let cached = cached.unwrap();
+                    return Ok((cached.body, cached.next));
+                }
+                StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {
+                    match rate_limit_wait(&response) {
+                        Some(wait) if wait <= max_wait && attempts < MAX_RATE_LIMIT_RETRIES => {
+                            attempts += 1;
+                            info!("GitHub is rate limiting us, retrying in {} seconds", wait);
+                            thread::sleep(Duration::from_secs(wait));
+                            continue;
+                        }
+                        Some(wait) => {
+                            return Err(format!(
+                                "GitHub is rate limiting you, and won't accept requests for \
+                                 another {} seconds. Try configuring an API token, moving to a \
+                                 internet connection that isn't shared, and/or disabling VPNs.",
+                                wait
+                            ));
+                        }
+                        None => {
+                            return Err(format!(
+                                "GitHub refused access to {:?}. Check the repository name and \
+                                 token.",
+                                url
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    return Err(format!("Bad status code: {:?}.", response.status()));
+                }
+            }
+
+            let etag = response
+                .headers()
+                .get(ETAG)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string());
+
+            let next = response
+                .headers()
+                .get(LINK)
+                .and_then(|x| x.to_str().ok())
+                .and_then(parse_next_link);
+
+            let body = response
+                .text()
+                .map_err(|x| format!("Failed to decode HTTP response body: {:?}", x))?;
+
+            if let (Some(etag), Some(cache_file)) = (etag, cache_file) {
+                let cached = CachedResponse {
+                    etag,
+                    body: body.clone(),
+                    next: next.clone(),
+                };
+
+                let file = cache_file
+                    .parent()
+                    .map_or(Ok(()), create_dir_all)
+                    .and_then(|_| FsFile::create(&cache_file));
+
+                match file {
+                    Ok(file) => {
+                        if let Err(v) = serde_json::to_writer(file, &cached) {
+                            warn!("Failed to cache GitHub response: {:?}", v);
+                        }
+                    }
+                    Err(v) => warn!("Failed to cache GitHub response: {:?}", v),
+                }
+            }
+
+            return Ok((body, next));
+        }
+    }
+}
+
+/// Builds a file name for a cached response from the request URL.
+fn cache_key(url: &str) -> String {
+    url.chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+        .collect()
+}
+
+/// Works out how long the server has asked us to wait, in seconds, if it has.
+fn rate_limit_wait(response: &Response) -> Option<u64> {
+    let headers = response.headers();
+
+    if let Some(retry_after) = headers
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok())
+    {
+        return Some(retry_after);
+    }
+
+    let remaining = headers
+        .get("x-ratelimit-remaining")
+        .and_then(|x| x.to_str().ok());
+
+    if remaining != Some("0") {
+        return None;
+    }
+
+    let reset = headers
+        .get("x-ratelimit-reset")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok())?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    Some(reset.saturating_sub(now) + 1)
 }
 
 impl ReleaseSource for GithubReleases {
-    fn get_current_releases(&self, config: &TomlValue) -> Result<Vec<Release>, String> {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
         // Reparse our Config as strongly typed
         let config: GithubConfig = match config.clone().try_into() {
             Ok(v) => v,
             Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
         };
 
+        let tag_prefix = match config.tag_prefix {
+            Some(ref v) => Some(
+                Regex::new(&format!("^(?:{})", v))
+                    .map_err(|x| format!("An error occurred while compiling regex: {:?}", x))?,
+            ),
+            None => None,
+        };
+
+        let token = config
+            .token
+            .clone()
+            .or_else(|| env::var(&config.token_env).ok());
+
+        let cache_dir: Option<PathBuf> = cache_dir.map(|x| x.join("github"));
+
         let mut results: Vec<Release> = Vec::new();
 
         // Build the HTTP client up
         let client = build_client()?;
-        let mut response = client
-            .get(&format!(
-                "https://api.github.com/repos/{}/releases",
-                config.repo
-            )).header(USER_AGENT, "liftinstall (j-selby)")
-            .send()
-            .map_err(|x| format!("Error while sending HTTP request: {:?}", x))?;
-
-        match response.status() {
-            StatusCode::OK => {}
-            StatusCode::FORBIDDEN => {
-                return Err(format!(
-                    "GitHub is rate limiting you. Try moving to a internet connection \
-                     that isn't shared, and/or disabling VPNs."
-                ));
+
+        let mut entries: Vec<serde_json::Value> = Vec::new();
+        let mut url = Some(format!(
+            "https://api.github.com/repos/{}/releases?per_page=100",
+            config.repo
+        ));
+        let mut pages = 0;
+
+        while let Some(page_url) = url.take() {
+            if pages >= config.max_pages {
+                warn!(
+                    "Stopped fetching releases for {:?} after {} pages",
+                    config.repo, pages
+                );
+                break;
             }
-            _ => {
-                return Err(format!("Bad status code: {:?}.", response.status()));
+            pages += 1;
+
+            let (body, next) = GithubReleases::fetch_page(
+                &client,
+                &page_url,
+                token.as_ref().map(|x| x.as_str()),
+                cache_dir.as_ref().map(|x| x.as_path()),
+                config.max_rate_limit_wait,
+            )?;
+
+            let result: serde_json::Value = serde_json::from_str(&body)
+                .map_err(|x| format!("Failed to parse response: {:?}", x))?;
+
+            match result {
+                serde_json::Value::Array(v) => entries.extend(v),
+                _ => return Err("Response was not an array!".to_string()),
             }
-        }
 
-        let body = response
-            .text()
-            .map_err(|x| format!("Failed to decode HTTP response body: {:?}", x))?;
+            url = next;
+        }
 
-        let result: serde_json::Value = serde_json::from_str(&body)
-            .map_err(|x| format!("Failed to parse response: {:?}", x))?;
+        // Prefer the tag as a version, as IDs only reflect when a release was created
+        let parse_tag = |entry: &serde_json::Value| {
+            entry["tag_name"]
+                .as_str()
+                .and_then(|tag| match tag_prefix {
+                    Some(ref regex) => Version::from_tag(&regex.replace(tag, "")),
+                    None => Version::from_tag(tag),
+                })
+        };
 
-        let result: &Vec<serde_json::Value> = result
-            .as_array()
-            .ok_or_else(|| "Response was not an array!".to_string())?;
+        // Release IDs can't be compared with semver versions, so only fall back to them
+        // if no tag could be parsed as one
+        let any_semver = entries.iter().any(|entry| match parse_tag(entry) {
+            Some(Version::Semver(_)) => true,
+            _ => false,
+        });
 
         // Parse JSON from server
-        for entry in result.iter() {
+        for entry in entries.iter() {
             let mut files = Vec::new();
 
             let id: u64 = match entry["id"].as_u64() {
@@ -78,6 +322,26 @@ impl ReleaseSource for GithubReleases {
                 None => return Err("JSON payload missing information about ID".to_string()),
             };
 
+            let tag = entry["tag_name"].as_str().map(|x| x.to_string());
+
+            let version = match parse_tag(entry) {
+                Some(Version::Integer(_)) | None if any_semver => {
+                    warn!(
+                        "Unable to parse tag {:?} as a semver version, skipping release",
+                        tag
+                    );
+                    continue;
+                }
+                Some(v) => v,
+                None => {
+                    warn!(
+                        "Unable to parse tag {:?} as a version, falling back to release ID",
+                        tag
+                    );
+                    Version::new_number(id)
+                }
+            };
+
             let assets = match entry["assets"].as_array() {
                 Some(v) => v,
                 None => return Err("JSON payload not an array".to_string()),
@@ -101,12 +365,19 @@ impl ReleaseSource for GithubReleases {
                 files.push(File {
                     name: string.to_string(),
                     url: url.to_string(),
+                    size: asset["size"].as_u64(),
+                    sha256: None,
+                    mirrors: Vec::new(),
                 });
             }
 
             results.push(Release {
-                version: Version::new_number(id),
+                version,
                 files,
+                tag,
+                name: entry["name"].as_str().map(|x| x.to_string()),
+                prerelease: entry["prerelease"].as_bool().unwrap_or(false),
+                channel: None,
             });
         }
 
@@ -0,0 +1,278 @@
+//! gitlab/mod.rs
+//!
+//! Contains the GitLab API implementation of a release source.
+
+use reqwest::header::{LINK, USER_AGENT};
+use reqwest::StatusCode;
+
+use serde_json;
+
+use std::path::Path;
+
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use sources::next_page;
+use sources::types::*;
+
+use http::build_client;
+
+pub struct GitlabReleases {}
+
+/// The configuration for this release.
+#[derive(Serialize, Deserialize)]
+struct GitlabConfig {
+    /// The project path (e.g. "group/project") or numeric ID.
+    project: String,
+    #[serde(default = "default_base_url")]
+    base_url: String,
+    #[serde(default)]
+    token: Option<String>,
+    /// The maximum number of pages of releases to fetch.
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+}
+
+fn default_base_url() -> String {
+    "https://gitlab.com".to_string()
+}
+
+fn default_max_pages() -> u32 {
+    10
+}
+
+impl GitlabReleases {
+    pub fn new() -> Self {
+        GitlabReleases {}
+    }
+}
+
+impl ReleaseSource for GitlabReleases {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        _cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
+        // Reparse our Config as strongly typed
+        let config: GitlabConfig = match config.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
+        };
+
+        let mut results: Vec<Release> = Vec::new();
+
+        // Build the HTTP client up
+        let client = build_client()?;
+
+        let mut entries: Vec<serde_json::Value> = Vec::new();
+        let mut page = Some(1);
+
+        while let Some(current) = page.take() {
+            if current > config.max_pages {
+                warn!(
+                    "Stopped fetching releases for {:?} after {} pages",
+                    config.project, config.max_pages
+                );
+                break;
+            }
+
+            let mut request = client
+                .get(&format!(
+                    "{}/api/v4/projects/{}/releases?per_page=100&page={}",
+                    config.base_url.trim_end_matches('/'),
+                    utf8_percent_encode(&config.project, PATH_SEGMENT_ENCODE_SET),
+                    current
+                )).header(USER_AGENT, "liftinstall (j-selby)");
+
+            if let Some(ref token) = config.token {
+                request = request.header("PRIVATE-TOKEN", token.as_str());
+            }
+
+            let mut response = request
+                .send()
+                .map_err(|x| format!("Error while sending HTTP request: {:?}", x))?;
+
+            match response.status() {
+                StatusCode::OK => {}
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+                    return Err(format!(
+                        "GitLab refused access to {:?} ({:?}). Check the project name and token.",
+                        config.project,
+                        response.status()
+                    ));
+                }
+                _ => {
+                    return Err(format!("Bad status code: {:?}.", response.status()));
+                }
+            }
+
+            let link = response
+                .headers()
+                .get(LINK)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string());
+
+            let body = response
+                .text()
+                .map_err(|x| format!("Failed to decode HTTP response body: {:?}", x))?;
+
+            let result: serde_json::Value = serde_json::from_str(&body)
+                .map_err(|x| format!("Failed to parse response: {:?}", x))?;
+
+            match result {
+                serde_json::Value::Array(v) => {
+                    page = next_page(link.as_ref().map(|x| x.as_str()), current, v.is_empty());
+                    entries.extend(v);
+                }
+                _ => return Err("Response was not an array!".to_string()),
+            }
+        }
+
+        // Parse JSON from server
+        for entry in entries.iter() {
+            let mut files = Vec::new();
+
+            let tag = match entry["tag_name"].as_str() {
+                Some(v) => v,
+                None => return Err("JSON payload missing information about tag".to_string()),
+            };
+
+            // GitLab releases don't carry a numeric ordering, so the tag must be a version
+            let version = match Version::from_tag(tag) {
+                Some(v) => v,
+                None => {
+                    warn!("Skipping GitLab release with unparsable tag {:?}", tag);
+                    continue;
+                }
+            };
+
+            let links = match entry["assets"]["links"].as_array() {
+                Some(v) => v,
+                None => return Err("JSON payload not an array".to_string()),
+            };
+
+            for link in links.iter() {
+                let string = match link["name"].as_str() {
+                    Some(v) => v,
+                    None => {
+                        return Err("JSON payload missing information about release name".to_string())
+                    }
+                };
+
+                let url = match link["direct_asset_url"]
+                    .as_str()
+                    .or_else(|| link["url"].as_str())
+                {
+                    Some(v) => v,
+                    None => {
+                        return Err("JSON payload missing information about release URL".to_string())
+                    }
+                };
+
+                files.push(File {
+                    name: string.to_string(),
+                    url: url.to_string(),
+                    size: None,
+                    sha256: None,
+                    mirrors: Vec::new(),
+                });
+            }
+
+            results.push(Release {
+                version,
+                files,
+                tag: Some(tag.to_string()),
+                name: entry["name"].as_str().map(|x| x.to_string()),
+                prerelease: entry["upcoming_release"].as_bool().unwrap_or(false),
+                channel: None,
+            });
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sources::serve_http;
+
+    fn release(tag: &str) -> String {
+        format!(
+            "{{\"id\": 1, \"tag_name\": {:?}, \"assets\": {{\"links\": []}}}}",
+            tag
+        )
+    }
+
+    fn versions(config: &str) -> Vec<String> {
+        let config: TomlValue = ::toml::from_str(config).unwrap();
+
+        GitlabReleases::new()
+            .get_current_releases(&config, None)
+            .unwrap()
+            .iter()
+            .map(|x| x.version.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn follows_link_headers() {
+        let (url, server) = serve_http(vec![
+            (
+                "Link: <http://next>; rel=\"next\"\r\n",
+                format!("[{}, {}]", release("v1.0.2"), release("v1.0.1")),
+            ),
+            (
+                "Link: <http://first>; rel=\"first\"\r\n",
+                format!("[{}]", release("v1.0.0")),
+            ),
+        ]);
+
+        assert_eq!(
+            versions(&format!(
+                "project = \"group/project\"\nbase_url = {:?}",
+                url
+            )),
+            vec!["1.0.2", "1.0.1", "1.0.0"]
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "/api/v4/projects/group%2Fproject/releases?per_page=100&page=1",
+                "/api/v4/projects/group%2Fproject/releases?per_page=100&page=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn requests_pages_until_one_is_empty() {
+        let (url, server) = serve_http(vec![
+            ("", format!("[{}]", release("v1.0.1"))),
+            ("", format!("[{}]", release("v1.0.0"))),
+            ("", "[]".to_string()),
+        ]);
+
+        assert_eq!(
+            versions(&format!(
+                "project = \"group/project\"\nbase_url = {:?}",
+                url
+            )),
+            vec!["1.0.1", "1.0.0"]
+        );
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn stops_after_max_pages() {
+        let (url, server) = serve_http(vec![("", format!("[{}]", release("v1.0.1")))]);
+
+        assert_eq!(
+            versions(&format!(
+                "{}\nmax_pages = 1",
+                format!("project = \"group/project\"\nbase_url = {:?}", url)
+            )),
+            vec!["1.0.1"]
+        );
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
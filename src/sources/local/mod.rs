@@ -0,0 +1,280 @@
+//! local/mod.rs
+//!
+//! Contains a release source which scans a local directory for packages, for use
+//! in offline installs.
+
+use std::env;
+use std::fs::read_dir;
+use std::path::Path;
+use std::path::PathBuf;
+
+use regex::Regex;
+
+use url::Url;
+
+use sources::types::*;
+
+pub struct LocalReleases {}
+
+/// The configuration for this release.
+#[derive(Serialize, Deserialize)]
+struct LocalConfig {
+    /// A directory path or file:// URL. Relative paths are relative to the installer.
+    path: String,
+    /// Extracts a version from a file name, using either a group named "version" or
+    /// the first capture group.
+    #[serde(default = "default_version_regex")]
+    version_regex: String,
+}
+
+/// Pre-release parts after the first are numeric (e.g. "-beta.1"), so that file
+/// extensions aren't taken as part of the version.
+fn default_version_regex() -> String {
+    r"(\d+\.\d+\.\d+(?:-[0-9A-Za-z-]+(?:\.\d+)*)?)".to_string()
+}
+
+impl LocalReleases {
+    pub fn new() -> Self {
+        LocalReleases {}
+    }
+}
+
+impl ReleaseSource for LocalReleases {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        _cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
+        // Reparse our Config as strongly typed
+        let config: LocalConfig = match config.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
+        };
+
+        let regex = Regex::new(&config.version_regex)
+            .map_err(|x| format!("An error occurred while compiling regex: {:?}", x))?;
+
+        let path = if config.path.starts_with("file://") {
+            Url::parse(&config.path)
+                .map_err(|x| format!("Invalid file URL: {:?}", x))?
+                .to_file_path()
+                .map_err(|_| format!("URL {:?} is not a valid local path", config.path))?
+        } else {
+            let path = PathBuf::from(&config.path);
+            if path.is_relative() {
+                let current_exe = env::current_exe()
+                    .map_err(|x| format!("Current executable could not be found: {:?}", x))?;
+                match current_exe.parent() {
+                    Some(v) => v.join(path),
+                    None => path,
+                }
+            } else {
+                path
+            }
+        };
+
+        let entries = read_dir(&path)
+            .map_err(|x| format!("Failed to read package directory {:?}: {:?}", path, x))?;
+
+        let mut results: Vec<Release> = Vec::new();
+
+        for entry in entries {
+            let entry = entry.map_err(|x| format!("Failed to read directory entry: {:?}", x))?;
+            let file_path = entry.path();
+
+            if !file_path.is_file() {
+                continue;
+            }
+
+            let name = match file_path.file_name().and_then(|x| x.to_str()) {
+                Some(v) => v.to_string(),
+                None => continue,
+            };
+
+            let version = match regex.captures(&name) {
+                Some(captures) => match captures.name("version").or_else(|| captures.get(1)) {
+                    Some(v) => Version::parse(v.as_str()),
+                    None => None,
+                },
+                None => None,
+            };
+
+            let version = match version {
+                Some(v) => v,
+                None => {
+                    info!("Ignoring {:?}, as no version could be found in its name", name);
+                    continue;
+                }
+            };
+
+            let url = Url::from_file_path(&file_path)
+                .map_err(|_| format!("Unable to build URL for {:?}", file_path))?;
+
+            let file = File {
+                name,
+                url: url.into_string(),
+                size: entry.metadata().ok().map(|x| x.len()),
+                sha256: None,
+                mirrors: Vec::new(),
+            };
+
+            // Group files sharing a version into a single release
+            if let Some(release) = results.iter_mut().find(|x| x.version == version) {
+                release.files.push(file);
+                continue;
+            }
+
+            results.push(Release {
+                version,
+                files: vec![file],
+                tag: None,
+                name: None,
+                prerelease: false,
+                channel: None,
+            });
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("local-test-{}-{}", name, ::std::process::id()));
+        if path.exists() {
+            remove_dir_all(&path).unwrap();
+        }
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Lists releases, sorted by version, with the sorted names of their files.
+    fn releases(config: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+        let config: TomlValue = ::toml::from_str(config).unwrap();
+
+        let mut releases = LocalReleases::new()
+            .get_current_releases(&config, None)?
+            .into_iter()
+            .map(|x| {
+                let mut files = x.files.into_iter().map(|x| x.name).collect::<Vec<_>>();
+                files.sort();
+                (x.version, files)
+            })
+            .collect::<Vec<_>>();
+
+        releases.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(releases
+            .into_iter()
+            .map(|(version, files)| (version.to_string(), files))
+            .collect())
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn lists_releases_by_version_in_names() {
+        let dir = scratch_dir("versions");
+        write(dir.join("app-1.0.0.zip"), "1").unwrap();
+        write(dir.join("app-1.1.0-beta.1.tar.gz"), "beta").unwrap();
+        write(dir.join("app-1.1.0.zip"), "1.1").unwrap();
+        write(dir.join("app-1.1.0.tar.gz"), "1.1").unwrap();
+
+        let config = format!("path = {:?}", dir.to_str().unwrap());
+
+        assert_eq!(
+            releases(&config).unwrap(),
+            vec![
+                ("1.0.0".to_string(), strings(&["app-1.0.0.zip"])),
+                (
+                    "1.1.0-beta.1".to_string(),
+                    strings(&["app-1.1.0-beta.1.tar.gz"])
+                ),
+                (
+                    "1.1.0".to_string(),
+                    strings(&["app-1.1.0.tar.gz", "app-1.1.0.zip"])
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn describes_files() {
+        let dir = scratch_dir("files");
+        write(dir.join("app-2.0.0.zip"), "contents").unwrap();
+
+        let config: TomlValue =
+            ::toml::from_str(&format!("path = {:?}", dir.to_str().unwrap())).unwrap();
+        let releases = LocalReleases::new()
+            .get_current_releases(&config, None)
+            .unwrap();
+
+        let file = &releases[0].files[0];
+        assert_eq!(
+            file.url,
+            Url::from_file_path(dir.join("app-2.0.0.zip"))
+                .unwrap()
+                .into_string()
+        );
+        assert_eq!(file.size, Some(8));
+        assert_eq!(file.sha256, None);
+    }
+
+    #[test]
+    fn ignores_entries_without_versions() {
+        let dir = scratch_dir("ignored");
+        write(dir.join("app-1.0.0.zip"), "1").unwrap();
+        write(dir.join("README.txt"), "").unwrap();
+        write(dir.join("app-latest.zip"), "").unwrap();
+        create_dir_all(dir.join("app-2.0.0")).unwrap();
+
+        let config = format!("path = {:?}", dir.to_str().unwrap());
+
+        assert_eq!(
+            releases(&config).unwrap(),
+            vec![("1.0.0".to_string(), strings(&["app-1.0.0.zip"]))]
+        );
+    }
+
+    #[test]
+    fn uses_version_regex() {
+        let dir = scratch_dir("regex");
+        write(dir.join("app_r3.zip"), "3").unwrap();
+        write(dir.join("app-1.0.0.zip"), "1").unwrap();
+
+        let config = format!(
+            "path = {:?}\nversion_regex = 'app_r(?P<version>\\d+)\\.zip'",
+            dir.to_str().unwrap()
+        );
+
+        assert_eq!(
+            releases(&config).unwrap(),
+            vec![("3".to_string(), strings(&["app_r3.zip"]))]
+        );
+    }
+
+    #[test]
+    fn accepts_file_urls() {
+        let dir = scratch_dir("url");
+        write(dir.join("app-1.0.0.zip"), "1").unwrap();
+
+        let url = Url::from_directory_path(&dir).unwrap();
+
+        assert_eq!(releases(&format!("path = \"{}\"", url)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_missing_directories() {
+        let dir = scratch_dir("missing").join("missing");
+
+        assert!(releases(&format!("path = {:?}", dir.to_str().unwrap())).is_err());
+        assert!(releases("version_regex = '.*'").is_err());
+    }
+}
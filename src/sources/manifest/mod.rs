@@ -0,0 +1,304 @@
+//! manifest/mod.rs
+//!
+//! Contains a release source backed by a static JSON or TOML manifest, hosted on any
+//! web server.
+
+use serde_json;
+use toml;
+
+use url::Url;
+
+use std::path::Path;
+
+use sources::types::*;
+
+use http::download_text;
+
+pub struct ManifestReleases {}
+
+/// The configuration for this release.
+#[derive(Serialize, Deserialize)]
+struct ManifestConfig {
+    url: String,
+    /// Either "json" or "toml". Guessed from the URL if not specified.
+    #[serde(default)]
+    format: Option<String>,
+}
+
+/// The root of a release manifest.
+#[derive(Deserialize)]
+struct Manifest {
+    releases: Vec<ManifestRelease>,
+}
+
+/// A single release listed in a manifest.
+#[derive(Deserialize)]
+struct ManifestRelease {
+    version: String,
+    #[serde(default)]
+    tag: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    channel: Option<String>,
+    files: Vec<ManifestFile>,
+}
+
+/// A single file listed in a manifest release. URLs may be relative to the manifest.
+#[derive(Deserialize)]
+struct ManifestFile {
+    name: String,
+    url: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    sha256: Option<String>,
+    /// Alternative URLs serving the same file
+    #[serde(default)]
+    mirrors: Vec<String>,
+}
+
+impl ManifestReleases {
+    pub fn new() -> Self {
+        ManifestReleases {}
+    }
+}
+
+impl ReleaseSource for ManifestReleases {
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        _cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String> {
+        // Reparse our Config as strongly typed
+        let config: ManifestConfig = match config.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => return Err(format!("Failed to parse release config: {:?}", v)),
+        };
+
+        let base_url =
+            Url::parse(&config.url).map_err(|x| format!("Invalid manifest URL: {:?}", x))?;
+
+        let format = match config.format {
+            Some(ref v) => v.to_lowercase(),
+            None if base_url.path().ends_with(".toml") => "toml".to_string(),
+            None => "json".to_string(),
+        };
+
+        let body = download_text(&config.url)?;
+
+        let manifest: Manifest = match format.as_str() {
+            "json" => serde_json::from_str(&body)
+                .map_err(|x| format!("Failed to parse manifest: {:?}", x))?,
+            "toml" => {
+                toml::from_str(&body).map_err(|x| format!("Failed to parse manifest: {:?}", x))?
+            }
+            _ => return Err(format!("Unknown manifest format: {:?}", format)),
+        };
+
+        let mut results: Vec<Release> = Vec::new();
+
+        for release in manifest.releases {
+            let version = match Version::parse(&release.version) {
+                Some(v) => v,
+                None => {
+                    return Err(format!(
+                        "Manifest contains an unparsable version: {:?}",
+                        release.version
+                    ))
+                }
+            };
+
+            let mut files = Vec::new();
+
+            for file in release.files {
+                let url = base_url
+                    .join(&file.url)
+                    .map_err(|x| format!("Invalid URL for {:?}: {:?}", file.name, x))?;
+
+                let mut mirrors = Vec::new();
+                for mirror in &file.mirrors {
+                    let mirror = base_url
+                        .join(mirror)
+                        .map_err(|x| format!("Invalid mirror for {:?}: {:?}", file.name, x))?;
+                    mirrors.push(mirror.into_string());
+                }
+
+                files.push(File {
+                    name: file.name,
+                    url: url.into_string(),
+                    size: file.size,
+                    sha256: file.sha256,
+                    mirrors,
+                });
+            }
+
+            results.push(Release {
+                version,
+                files,
+                tag: release.tag,
+                name: release.name,
+                prerelease: release.prerelease,
+                channel: release.channel,
+            });
+        }
+
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Gives each manifest its own directory, as tests run in parallel.
+    static MANIFESTS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Writes a manifest to a file at the specified path, and reads releases from it
+    /// using extra config.
+    fn releases(path: &str, options: &str, body: &str) -> Result<Vec<Release>, String> {
+        let root = env::temp_dir().join(format!(
+            "manifest-test-{}-{}",
+            ::std::process::id(),
+            MANIFESTS.fetch_add(1, Ordering::SeqCst)
+        ));
+        if root.exists() {
+            remove_dir_all(&root).unwrap();
+        }
+
+        let file = root.join(path);
+        create_dir_all(file.parent().unwrap()).unwrap();
+        write(&file, body).unwrap();
+
+        let url = Url::from_file_path(&file).unwrap();
+        let config: TomlValue =
+            ::toml::from_str(&format!("url = \"{}\"\n{}", url, options)).unwrap();
+
+        ManifestReleases::new().get_current_releases(&config, None)
+    }
+
+    #[test]
+    fn parses_json_manifests() {
+        let releases = releases(
+            "app/releases.json",
+            "",
+            r#"{"releases": [
+                {
+                    "version": "1.1.0",
+                    "tag": "v1.1.0",
+                    "name": "Beta",
+                    "prerelease": true,
+                    "channel": "beta",
+                    "files": [{
+                        "name": "app.zip",
+                        "url": "files/app.zip",
+                        "size": 1024,
+                        "sha256": "abc",
+                        "mirrors": ["https://mirror.example.com/app.zip", "/mirror/app.zip"]
+                    }]
+                },
+                {
+                    "version": "1.0.0",
+                    "files": [{"name": "app.zip", "url": "https://example.com/app.zip"}]
+                }
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(releases.len(), 2);
+
+        let beta = &releases[0];
+        assert_eq!(beta.version.to_string(), "1.1.0");
+        assert_eq!(beta.tag, Some("v1.1.0".to_string()));
+        assert_eq!(beta.name, Some("Beta".to_string()));
+        assert!(beta.prerelease);
+        assert_eq!(beta.channel, Some("beta".to_string()));
+
+        let file = &beta.files[0];
+        assert!(file.url.ends_with("/app/files/app.zip"), "{}", file.url);
+        assert_eq!(file.size, Some(1024));
+        assert_eq!(file.sha256, Some("abc".to_string()));
+        assert_eq!(file.mirrors[0], "https://mirror.example.com/app.zip");
+        assert_eq!(file.mirrors[1], "file:///mirror/app.zip");
+
+        let stable = &releases[1];
+        assert_eq!(stable.version.to_string(), "1.0.0");
+        assert_eq!(stable.tag, None);
+        assert!(!stable.prerelease);
+        assert_eq!(stable.files[0].url, "https://example.com/app.zip");
+        assert_eq!(stable.files[0].size, None);
+        assert!(stable.files[0].mirrors.is_empty());
+    }
+
+    #[test]
+    fn guesses_toml_from_url() {
+        let releases = releases(
+            "releases.toml",
+            "",
+            "[[releases]]\n\
+             version = \"2.0.0\"\n\
+             [[releases.files]]\n\
+             name = \"app.tar.gz\"\n\
+             url = \"app.tar.gz\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].version.to_string(), "2.0.0");
+        assert_eq!(releases[0].files[0].name, "app.tar.gz");
+    }
+
+    #[test]
+    fn uses_configured_format() {
+        let body = "[[releases]]\nversion = \"2.0.0\"\nfiles = []\n";
+
+        assert!(releases("releases", "format = \"TOML\"", body).is_ok());
+        assert!(releases("releases", "", body).is_err());
+        assert!(releases("releases", "format = \"yaml\"", body).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        // No files
+        assert!(releases("a.json", "", r#"{"releases": [{"version": "1.0.0"}]}"#).is_err());
+        // File without a URL
+        assert!(releases(
+            "a.json",
+            "",
+            r#"{"releases": [{"version": "1.0.0", "files": [{"name": "a.zip"}]}]}"#
+        )
+        .is_err());
+        // No releases at all
+        assert!(releases("a.json", "", "{}").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_versions() {
+        let result = releases(
+            "a.json",
+            "",
+            r#"{"releases": [{"version": "not a version", "files": []}]}"#,
+        );
+
+        assert!(result.unwrap_err().contains("unparsable version"));
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let config: TomlValue = ::toml::from_str("format = \"json\"").unwrap();
+        assert!(ManifestReleases::new()
+            .get_current_releases(&config, None)
+            .is_err());
+
+        let config: TomlValue = ::toml::from_str("url = \"not a url\"").unwrap();
+        assert!(ManifestReleases::new()
+            .get_current_releases(&config, None)
+            .is_err());
+    }
+}
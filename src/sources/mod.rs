@@ -4,14 +4,142 @@
 
 pub mod types;
 
+pub mod embedded;
+pub mod gitea;
 pub mod github;
+pub mod gitlab;
+pub mod local;
+pub mod manifest;
 
 use self::types::ReleaseSource;
 
 /// Returns a ReleaseSource by a name, if possible
 pub fn get_by_name(name: &str) -> Option<Box<ReleaseSource>> {
     match name {
+        "embedded" => Some(Box::new(embedded::EmbeddedReleases::new())),
         "github" => Some(Box::new(github::GithubReleases::new())),
+        "gitlab" => Some(Box::new(gitlab::GitlabReleases::new())),
+        "gitea" => Some(Box::new(gitea::GiteaReleases::new())),
+        "local" => Some(Box::new(local::LocalReleases::new())),
+        "manifest" => Some(Box::new(manifest::ManifestReleases::new())),
         _ => None,
     }
 }
+
+/// Finds the URL marked as rel="next" in a Link header.
+pub fn parse_next_link(header: &str) -> Option<String> {
+    for link in header.split(',') {
+        let mut parts = link.split(';');
+
+        let url = parts.next()?.trim();
+        if !url.starts_with('<') || !url.ends_with('>') {
+            continue;
+        }
+
+        if parts.any(|x| x.trim() == "rel=\"next\"") {
+            return Some(url[1..url.len() - 1].to_string());
+        }
+    }
+
+    None
+}
+
+/// Works out which page of a paginated API follows `page`, if any. Servers which send a
+/// Link header say if there is another page; otherwise pages are requested until one
+/// comes back empty.
+pub fn next_page(link: Option<&str>, page: u32, empty: bool) -> Option<u32> {
+    if empty {
+        return None;
+    }
+
+    match link {
+        Some(link) => parse_next_link(link).map(|_| page + 1),
+        None => Some(page + 1),
+    }
+}
+
+/// Serves a response to each of the next requests to a local HTTP server, returning
+/// its base URL and a handle which returns the paths requested.
+#[cfg(test)]
+pub fn serve_http(
+    responses: Vec<(&'static str, String)>,
+) -> (String, ::std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = thread::spawn(move || {
+        let mut paths = Vec::new();
+
+        for (headers, body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            paths.push(request.split(' ').nth(1).unwrap_or("").to_string());
+
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+            }
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                body.len(),
+                headers,
+                body
+            )
+            .unwrap();
+        }
+
+        paths
+    });
+
+    (url, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_next_link_finds_next_page() {
+        let header = "<https://a/releases?page=1>; rel=\"prev\", \
+                      <https://a/releases?page=3>; rel=\"next\", \
+                      <https://a/releases?page=5>; rel=\"last\"";
+
+        assert_eq!(
+            parse_next_link(header),
+            Some("https://a/releases?page=3".to_string())
+        );
+        assert_eq!(
+            parse_next_link("<https://a/releases?page=1>; rel=\"first\""),
+            None
+        );
+        assert_eq!(parse_next_link("https://a; rel=\"next\""), None);
+        assert_eq!(parse_next_link(""), None);
+    }
+
+    #[test]
+    fn next_page_follows_link_header() {
+        assert_eq!(
+            next_page(Some("<https://a>; rel=\"next\""), 1, false),
+            Some(2)
+        );
+        assert_eq!(next_page(Some("<https://a>; rel=\"prev\""), 2, false), None);
+    }
+
+    #[test]
+    fn next_page_without_link_header_stops_at_empty_page() {
+        assert_eq!(next_page(None, 1, false), Some(2));
+        assert_eq!(next_page(None, 2, true), None);
+        assert_eq!(next_page(Some("<https://a>; rel=\"next\""), 2, true), None);
+    }
+}
@@ -3,8 +3,12 @@
 //! Contains traits that should be implemented by sources, as well as corresponding types.
 
 use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::Display;
+use std::path::Path;
 
 pub use semver::Version as SemverVersion;
+pub use semver::VersionReq as SemverVersionReq;
 
 pub use toml::value::Value as TomlValue;
 
@@ -38,6 +42,51 @@ impl Version {
     pub fn new_number(version: u64) -> Version {
         Version::Integer(version)
     }
+
+    /// Parses a version from a string, preferring semver and falling back to a
+    /// plain integer.
+    pub fn parse(version: &str) -> Option<Version> {
+        let version = version.trim();
+
+        if let Ok(v) = SemverVersion::parse(version) {
+            return Some(Version::new_semver(v));
+        }
+
+        version.parse::<u64>().ok().map(Version::new_number)
+    }
+
+    /// Parses a version from a VCS tag name, ignoring a leading "v" (e.g. "v1.2.3").
+    pub fn from_tag(tag: &str) -> Option<Version> {
+        let tag = tag.trim();
+        let tag = if tag.starts_with('v') || tag.starts_with('V') {
+            &tag[1..]
+        } else {
+            tag
+        };
+
+        Version::parse(tag)
+    }
+
+    /// Checks if this version satisfies a requirement. Requirements are semver
+    /// requirements (e.g. "^1.2" or "=1.2.3"), or exact integer versions.
+    pub fn matches(&self, requirement: &str) -> bool {
+        if let Version::Semver(ref version) = *self {
+            if let Ok(requirement) = SemverVersionReq::parse(requirement) {
+                return requirement.matches(version);
+            }
+        }
+
+        self.to_string() == requirement.trim().trim_start_matches('=').trim()
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Version::Semver(ref version) => write!(f, "{}", version),
+            Version::Integer(ref version) => write!(f, "{}", version),
+        }
+    }
 }
 
 impl PartialOrd for Version {
@@ -62,24 +111,114 @@ impl Ord for Version {
 }
 
 /// A individual file in a release.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct File {
     pub name: String,
     pub url: String,
+    /// Size of the file in bytes, if the source knows it ahead of time
+    pub size: Option<u64>,
+    /// Hex-encoded SHA-256 digest of the file, if the source provides one
+    pub sha256: Option<String>,
+    /// Alternative URLs serving the same file
+    pub mirrors: Vec<String>,
 }
 
 impl File {}
 
 /// A individual release of an application.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Release {
     pub version: Version,
     pub files: Vec<File>,
+    /// The VCS tag this release was built from, if any
+    pub tag: Option<String>,
+    /// The human readable title of this release, if any
+    pub name: Option<String>,
+    /// If the source flagged this release as a prerelease
+    pub prerelease: bool,
+    /// The channel this release was published to, if the source knows it
+    pub channel: Option<String>,
 }
 
 /// A source of releases.
 pub trait ReleaseSource {
     /// Gets a list of the available releases from this source. Should cache internally
-    /// if possible using a mutex.
-    fn get_current_releases(&self, config: &TomlValue) -> Result<Vec<Release>, String>;
+    /// if possible using a mutex. `cache_dir` is a directory which persists between runs,
+    /// if an installation directory is known.
+    fn get_current_releases(
+        &self,
+        config: &TomlValue,
+        cache_dir: Option<&Path>,
+    ) -> Result<Vec<Release>, String>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn semver(version: &str) -> Option<Version> {
+        Some(Version::new_semver(SemverVersion::parse(version).unwrap()))
+    }
+
+    #[test]
+    fn from_tag_ignores_leading_v() {
+        assert_eq!(Version::from_tag("v1.2.3"), semver("1.2.3"));
+        assert_eq!(Version::from_tag("V1.2.3"), semver("1.2.3"));
+        assert_eq!(Version::from_tag(" v1.2.3 "), semver("1.2.3"));
+        assert_eq!(Version::from_tag("1.2.3"), semver("1.2.3"));
+        assert_eq!(Version::from_tag("v1.2.3-beta.1"), semver("1.2.3-beta.1"));
+    }
+
+    #[test]
+    fn from_tag_falls_back_to_integers() {
+        assert_eq!(Version::from_tag("42"), Some(Version::new_number(42)));
+        assert_eq!(Version::from_tag("v42"), Some(Version::new_number(42)));
+    }
+
+    #[test]
+    fn from_tag_rejects_other_tags() {
+        assert_eq!(Version::from_tag(""), None);
+        assert_eq!(Version::from_tag("v"), None);
+        assert_eq!(Version::from_tag("vv1.2.3"), None);
+        assert_eq!(Version::from_tag("nightly"), None);
+        assert_eq!(Version::from_tag("release-1.2.3"), None);
+        assert_eq!(Version::from_tag("1.2"), None);
+    }
+
+    #[test]
+    fn matches_semver_requirements() {
+        let version = semver("1.4.2").unwrap();
+
+        assert!(version.matches("=1.4.2"));
+        assert!(version.matches("~1.4"));
+        assert!(version.matches("^1.2"));
+        assert!(version.matches(">=1.0.0, <2.0.0"));
+        assert!(!version.matches("=1.4.1"));
+        assert!(!version.matches("~1.3"));
+        assert!(!version.matches("^2"));
+        // As with Cargo, a bare version is a caret requirement
+        assert!(version.matches("1.4.0"));
+        assert!(!version.matches("1.5.0"));
+    }
+
+    #[test]
+    fn matches_excludes_prereleases_unless_named() {
+        let version = semver("1.5.0-beta.1").unwrap();
+
+        assert!(!version.matches("^1.4"));
+        assert!(version.matches("=1.5.0-beta.1"));
+        assert!(version.matches(">=1.5.0-beta.0"));
+    }
+
+    #[test]
+    fn matches_integers_exactly() {
+        let version = Version::new_number(42);
+
+        assert!(version.matches("42"));
+        assert!(version.matches("=42"));
+        assert!(version.matches(" = 42 "));
+        assert!(!version.matches("4"));
+        assert!(!version.matches("^42"));
+        assert!(!version.matches(">=1"));
+    }
 }
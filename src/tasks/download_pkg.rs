@@ -1,4 +1,5 @@
-//! Downloads a package into memory.
+//! Downloads a package into the cache directory, resuming interrupted downloads and
+//! failing over between mirrors if needed.
 
 use installer::InstallerFramework;
 
@@ -10,16 +11,467 @@ use tasks::TaskParamType;
 
 use tasks::resolver::ResolvePackageTask;
 
-use http::stream_file;
+use http::stream_file_from;
+
+use checksum::verify_file;
+
+use delta;
+use delta::DeltaPaths;
+
+use signing::{Verifier, SIGNATURE_EXTENSION};
+
+use sources::types::File;
+use sources::types::Release;
+
+use std::cmp::Ordering;
+use std::fs::{create_dir_all, remove_dir_all, remove_file, rename, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use url::Url;
 
 use number_prefix::{decimal_prefix, Prefixed, Standalone};
 
 use logging::LoggingErrors;
 
+/// How many times an interrupted download is retried against the same mirror.
+const MAX_RESUME_ATTEMPTS: usize = 5;
+
 pub struct DownloadPackageTask {
     pub name: String,
 }
 
+/// Returns the origin (scheme, host and port) of a URL, used to track mirror speeds.
+fn mirror_origin(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+
+    match url.scheme() {
+        "http" | "https" => Some(url.origin().ascii_serialization()),
+        _ => None,
+    }
+}
+
+/// Lists every URL a file can be downloaded from. Mirrors which were fast last time are
+/// tried first, followed by untried ones, and then ones which have failed before.
+fn mirror_candidates(file: &File, context: &InstallerFramework) -> Vec<String> {
+    let mut candidates = vec![file.url.clone()];
+    candidates.extend(file.mirrors.iter().cloned());
+
+    if let Some(ref config) = context.config {
+        for rule in &config.mirrors {
+            candidates.extend(rule.apply(&file.url));
+        }
+    }
+
+    let mut unique: Vec<String> = Vec::new();
+    for candidate in candidates {
+        if !unique.contains(&candidate) {
+            unique.push(candidate);
+        }
+    }
+
+    let rank = |url: &String| -> (u8, f64) {
+        match mirror_origin(url).and_then(|x| context.database.mirror_speeds.get(&x).cloned()) {
+            Some(speed) if speed > 0.0 => (2, speed),
+            Some(_) => (0, 0.0),
+            None => (1, 0.0),
+        }
+    };
+
+    // This is a stable sort, so the source's ordering is kept for equal ranks
+    unique.sort_by(|a, b| {
+        let (a_tier, a_speed) = rank(a);
+        let (b_tier, b_speed) = rank(b);
+
+        b_tier
+            .cmp(&a_tier)
+            .then(b_speed.partial_cmp(&a_speed).unwrap_or(Ordering::Equal))
+    });
+
+    unique
+}
+
+impl DownloadPackageTask {
+    /// Checks to see if this release is the one already installed.
+    fn is_up_to_date(&self, context: &InstallerFramework, release: &Release) -> bool {
+        for element in &context.database.packages {
+            if element.name == self.name {
+                // Installs from before channels existed didn't record one
+                let same_channel = element.channel.is_none() || element.channel == release.channel;
+
+                if element.version == release.version && same_channel {
+                    info!("{:?} is already up to date.", self.name);
+                    return true;
+                }
+                break;
+            }
+        }
+
+        false
+    }
+
+    /// Checks a downloaded file against its expected digest and signature. Files which
+    /// fail are removed so that they aren't resumed or reused later.
+    fn verify(
+        &self,
+        context: &InstallerFramework,
+        release: &Release,
+        file: &File,
+        path: &Path,
+    ) -> Result<(), String> {
+        let result = Verifier::new(&context.base_attributes).and_then(|verifier| {
+            match file.sha256 {
+                Some(ref expected) => {
+                    verify_file(path, expected)?;
+                    info!("Verified checksum of {:?}", file.name);
+                }
+                None => info!("No checksum is available for {:?}", file.name),
+            }
+
+            // Prefer a signature published alongside the file in the release
+            let signature_name = format!("{}{}", file.name, SIGNATURE_EXTENSION);
+            let signature_url = match release.files.iter().find(|x| x.name == signature_name) {
+                Some(v) => v.url.clone(),
+                None => format!("{}{}", file.url, SIGNATURE_EXTENSION),
+            };
+
+            verifier.verify_file(&file.name, path, &signature_url)
+        });
+
+        if let Err(v) = result {
+            if let Err(e) = remove_file(path) {
+                warn!("Unable to remove rejected download {:?}: {:?}", path, e);
+            }
+
+            return Err(format!(
+                "Downloaded package {:?} failed verification: {}",
+                self.name, v
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Downloads a delta and applies it to the installed version of this package,
+    /// returning a directory containing the complete new version, and the files which
+    /// the delta left unchanged.
+    fn apply_delta(
+        &self,
+        context: &mut InstallerFramework,
+        messenger: &Fn(&TaskMessage),
+        release: &Release,
+        delta: &File,
+    ) -> Result<(PathBuf, Vec<String>), String> {
+        let installed = context
+            .database
+            .packages
+            .iter()
+            .find(|x| x.name == self.name)
+            .cloned()
+            .ok_or_else(|| format!("{:?} is not installed", self.name))?;
+
+        let delta_path = self.fetch(context, messenger, release, delta)?;
+
+        messenger(&TaskMessage::DisplayMessage(
+            &format!("Applying update to {:?}...", self.name),
+            1.0,
+        ));
+
+        let install_path = context
+            .install_path
+            .clone()
+            .log_expect("Should have an install path by now");
+
+        // Kept apart from where the package itself is staged, which this is read into
+        let mut staged_path = context
+            .get_staging_dir(&self.name)
+            .log_expect("Should have an install path by now")
+            .into_os_string();
+        staged_path.push(".patched");
+        let staged_path = PathBuf::from(staged_path);
+
+        if staged_path.exists() {
+            remove_dir_all(&staged_path)
+                .map_err(|x| format!("Unable to clean up staged files: {:?}", x))?;
+        }
+
+        let limits = context
+            .config
+            .as_ref()
+            .log_expect("Should have packages by now")
+            .extraction_limits
+            .clone();
+
+        let paths = DeltaPaths {
+            archive: &delta_path,
+            install_dir: &install_path,
+            installed_files: &installed.files,
+            target_dir: &staged_path,
+        };
+
+        let result = delta::apply_delta(
+            &delta.name,
+            &paths,
+            &installed.version,
+            &release.version,
+            &limits,
+        );
+
+        if let Err(v) = remove_file(&delta_path) {
+            warn!("Unable to remove downloaded delta {:?}: {:?}", delta_path, v);
+        }
+
+        match result {
+            Ok(unchanged) => Ok((staged_path, unchanged)),
+            Err(v) => {
+                if staged_path.exists() {
+                    if let Err(e) = remove_dir_all(&staged_path) {
+                        warn!("Unable to clean up staged files: {:?}", e);
+                    }
+                }
+
+                Err(v)
+            }
+        }
+    }
+
+    /// Downloads a file into the cache directory (or takes it from the shared download
+    /// cache), returning the path to the verified file.
+    fn fetch(
+        &self,
+        context: &mut InstallerFramework,
+        messenger: &Fn(&TaskMessage),
+        release: &Release,
+        file: &File,
+    ) -> Result<PathBuf, String> {
+        let download_dir = context
+            .get_cache_dir()
+            .log_expect("Should have an install path by now")
+            .join("downloads");
+
+        create_dir_all(&download_dir)
+            .map_err(|x| format!("Unable to create download directory: {:?}", x))?;
+
+        let file_name = format!("{}-{}-{}", self.name, release.version, file.name)
+            .replace(|c: char| c == '/' || c == '\\', "_");
+
+        let target_path = download_dir.join(&file_name);
+        let partial_path = download_dir.join(format!("{}.part", file_name));
+
+        // A previous attempt may have finished downloading, but failed to install
+        if target_path.exists() {
+            info!("Using previously downloaded copy of {:?}", self.name);
+            self.verify(context, release, file, &target_path)?;
+            return Ok(target_path);
+        }
+
+        let cache = context.get_download_cache();
+        let expected_sha256 = file.sha256.clone();
+        let sha256 = expected_sha256.as_ref().map(|x| x.as_str());
+
+        match cache.fetch(&file.url, sha256, &target_path) {
+            Ok(true) => {
+                info!("Using cached copy of {:?}", self.name);
+
+                match self.verify(context, release, file, &target_path) {
+                    Ok(_) => return Ok(target_path),
+                    Err(v) => {
+                        warn!("Discarding cached copy of {:?}: {}", self.name, v);
+
+                        if let Err(v) = cache.remove(&file.url, sha256) {
+                            warn!("Unable to remove file from the download cache: {}", v);
+                        }
+                    }
+                }
+            }
+            Ok(false) => {}
+            Err(v) => warn!("Unable to read from the download cache: {}", v),
+        }
+
+        let mut target = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&partial_path)
+            .map_err(|x| format!("Unable to open download file: {:?}", x))?;
+
+        let mut written = target
+            .metadata()
+            .map_err(|x| format!("Unable to read download file metadata: {:?}", x))?
+            .len();
+
+        let expected_size = file.size.unwrap_or(0);
+
+        // Discard partial downloads which can't possibly be for this file
+        if expected_size > 0 && written > expected_size {
+            warn!("Discarding oversized partial download of {:?}", self.name);
+            written = 0;
+        }
+
+        target
+            .set_len(written)
+            .and_then(|_| target.seek(SeekFrom::Start(written)))
+            .map_err(|x| format!("Unable to prepare download file: {:?}", x))?;
+
+        let complete = expected_size > 0 && written == expected_size;
+
+        if written > 0 && !complete {
+            info!("Resuming download of {:?} from {} bytes", self.name, written);
+        }
+
+        let candidates = mirror_candidates(file, context);
+
+        let mut last_error = None;
+
+        for url in candidates.iter().filter(|_| !complete) {
+            let start = Instant::now();
+            let mut transferred = 0;
+            let mut attempts = 0;
+
+            let result = loop {
+                let offset = written;
+
+                let result = stream_file_from(url, offset, |data, position, size| {
+                    if position != written {
+                        // The server doesn't support ranges, so start over
+                        if position != 0 {
+                            return Err(format!("Server sent unexpected range at {}", position));
+                        }
+
+                        target
+                            .set_len(0)
+                            .and_then(|_| target.seek(SeekFrom::Start(0)))
+                            .map_err(|x| format!("Unable to truncate download file: {:?}", x))?;
+
+                        written = 0;
+                    }
+
+                    target
+                        .write_all(&data)
+                        .map_err(|x| format!("Unable to write download file: {:?}", x))?;
+
+                    written += data.len() as u64;
+                    transferred += data.len() as u64;
+
+                    // Fall back to the size reported by the source if the server didn't send one
+                    let size = if size == 0 { expected_size } else { size };
+
+                    let percentage = if size == 0 {
+                        0.0
+                    } else {
+                        (written as f64) / (size as f64)
+                    };
+
+                    // Pretty print data volumes
+                    let pretty_current = match decimal_prefix(written as f64) {
+                        Standalone(bytes) => format!("{} bytes", bytes),
+                        Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+                    };
+                    let pretty_total = match decimal_prefix(size as f64) {
+                        Standalone(bytes) => format!("{} bytes", bytes),
+                        Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
+                    };
+
+                    messenger(&TaskMessage::DisplayMessage(
+                        &format!(
+                            "Downloading {} ({} of {})...",
+                            self.name, pretty_current, pretty_total
+                        ),
+                        percentage,
+                    ));
+
+                    Ok(())
+                });
+
+                match result {
+                    Ok(_) => break Ok(()),
+                    Err(v) => {
+                        if attempts >= MAX_RESUME_ATTEMPTS {
+                            break Err(v);
+                        }
+
+                        if written > offset {
+                            // Pick up from where the interrupted transfer stopped
+                            warn!("Download of {:?} was interrupted: {}", self.name, v);
+                        } else if offset > 0 {
+                            // The partial download might be stale or already complete
+                            warn!("Unable to resume download of {:?}: {}", self.name, v);
+
+                            target
+                                .set_len(0)
+                                .and_then(|_| target.seek(SeekFrom::Start(0)))
+                                .map_err(|x| {
+                                    format!("Unable to truncate download file: {:?}", x)
+                                })?;
+
+                            written = 0;
+                        } else {
+                            break Err(v);
+                        }
+
+                        attempts += 1;
+                    }
+                }
+            };
+
+            let origin = mirror_origin(url);
+
+            match result {
+                Ok(_) => {
+                    let elapsed = start.elapsed();
+                    let elapsed =
+                        elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+                    if let Some(origin) = origin {
+                        if transferred > 0 && elapsed > 0.0 {
+                            context
+                                .database
+                                .mirror_speeds
+                                .insert(origin, transferred as f64 / elapsed);
+                        }
+                    }
+
+                    last_error = None;
+                    break;
+                }
+                Err(v) => {
+                    warn!("Failed to download {:?} from {:?}: {}", self.name, url, v);
+
+                    if let Some(origin) = origin {
+                        context.database.mirror_speeds.insert(origin, 0.0);
+                    }
+
+                    last_error = Some(v);
+                }
+            }
+        }
+
+        if let Some(v) = last_error {
+            return Err(format!(
+                "Failed to download {:?} from any of {} location(s): {}",
+                self.name,
+                candidates.len(),
+                v
+            ));
+        }
+
+        drop(target);
+
+        self.verify(context, release, file, &partial_path)?;
+
+        rename(&partial_path, &target_path)
+            .map_err(|x| format!("Unable to move completed download: {:?}", x))?;
+
+        if let Err(v) = cache.store(&file.url, sha256, &target_path) {
+            warn!("Unable to add {:?} to the download cache: {}", self.name, v);
+        }
+
+        Ok(target_path)
+    }
+}
+
 impl Task for DownloadPackageTask {
     fn execute(
         &mut self,
@@ -29,21 +481,24 @@ impl Task for DownloadPackageTask {
     ) -> Result<TaskParamType, String> {
         assert_eq!(input.len(), 1);
 
+        if let Some(result) = context.prefetched.remove(&self.name) {
+            if let TaskParamType::DownloadedFile(ref release, _, _, _) = result {
+                if self.is_up_to_date(context, release) {
+                    return Ok(TaskParamType::Break);
+                }
+            }
+
+            return Ok(result);
+        }
+
         let file = input.pop().log_expect("Should have input from resolver!");
-        let (version, file) = match file {
-            TaskParamType::File(v, f) => (v, f),
+        let (release, file, delta) = match file {
+            TaskParamType::File(r, f, d) => (r, f, d),
             _ => return Err("Unexpected param type to download package".to_string()),
         };
 
-        // Check to see if this is the newest file available already
-        for element in &context.database.packages {
-            if element.name == self.name {
-                if element.version == version {
-                    info!("{:?} is already up to date.", self.name);
-                    return Ok(TaskParamType::Break);
-                }
-                break;
-            }
+        if self.is_up_to_date(context, &release) {
+            return Ok(TaskParamType::Break);
         }
 
         messenger(&TaskMessage::DisplayMessage(
@@ -51,42 +506,21 @@ impl Task for DownloadPackageTask {
             0.0,
         ));
 
-        let mut downloaded = 0;
-        let mut data_storage: Vec<u8> = Vec::new();
-
-        stream_file(&file.url, |data, size| {
-            {
-                data_storage.extend_from_slice(&data);
+        if let Some(delta) = delta {
+            match self.apply_delta(context, messenger, &release, &delta) {
+                Ok((path, unchanged)) => {
+                    return Ok(TaskParamType::DownloadedFile(release, file, path, unchanged));
+                }
+                Err(v) => warn!(
+                    "Unable to update {:?} using a delta, downloading it in full: {}",
+                    self.name, v
+                ),
             }
+        }
 
-            downloaded += data.len();
-
-            let percentage = if size == 0 {
-                0.0
-            } else {
-                (downloaded as f64) / (size as f64)
-            };
-
-            // Pretty print data volumes
-            let pretty_current = match decimal_prefix(downloaded as f64) {
-                Standalone(bytes) => format!("{} bytes", bytes),
-                Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
-            };
-            let pretty_total = match decimal_prefix(size as f64) {
-                Standalone(bytes) => format!("{} bytes", bytes),
-                Prefixed(prefix, n) => format!("{:.0} {}B", n, prefix),
-            };
-
-            messenger(&TaskMessage::DisplayMessage(
-                &format!(
-                    "Downloading {} ({} of {})...",
-                    self.name, pretty_current, pretty_total
-                ),
-                percentage,
-            ));
-        })?;
+        let path = self.fetch(context, messenger, &release, &file)?;
 
-        Ok(TaskParamType::FileContents(version, file, data_storage))
+        Ok(TaskParamType::DownloadedFile(release, file, path, Vec::new()))
     }
 
     fn dependencies(&self) -> Vec<TaskDependency> {
@@ -6,6 +6,7 @@ use tasks::ensure_only_instance::EnsureOnlyInstanceTask;
 use tasks::install_dir::VerifyInstallDirTask;
 use tasks::install_global_shortcut::InstallGlobalShortcutsTask;
 use tasks::install_pkg::InstallPackageTask;
+use tasks::prefetch::PrefetchPackagesTask;
 use tasks::save_executable::SaveExecutableTask;
 use tasks::uninstall_pkg::UninstallPackageTask;
 
@@ -47,6 +48,13 @@ impl Task for InstallTask {
             }),
         ));
 
+        elements.push(TaskDependency::build(
+            TaskOrdering::Pre,
+            Box::new(PrefetchPackagesTask {
+                items: self.items.clone(),
+            }),
+        ));
+
         for item in &self.items {
             elements.push(TaskDependency::build(
                 TaskOrdering::Pre,
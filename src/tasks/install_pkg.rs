@@ -2,10 +2,8 @@
 
 use installer::InstallerFramework;
 
-use tasks::download_pkg::DownloadPackageTask;
 use tasks::install_shortcuts::InstallShortcutsTask;
-use tasks::save_database::SaveDatabaseTask;
-use tasks::uninstall_pkg::UninstallPackageTask;
+use tasks::run_hook::{run_hook, HookKind, RunHookTask};
 use tasks::Task;
 use tasks::TaskDependency;
 use tasks::TaskMessage;
@@ -15,15 +13,485 @@ use tasks::TaskParamType;
 use config::PackageDescription;
 use installer::LocalInstallation;
 
+use std::collections::HashMap;
+use std::fs::copy;
 use std::fs::create_dir_all;
-use std::io::copy;
+use std::fs::read_dir;
+use std::fs::remove_dir;
+use std::fs::remove_dir_all;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::set_permissions;
+use std::fs::symlink_metadata;
+use std::fs::File;
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+
+use filetime::{set_file_times, FileTime};
+
+use glob::Pattern;
+
+use checksum::sha256_file;
 
 use logging::LoggingErrors;
 
+use native::create_symlink;
+
 use archives;
+use archives::{check_within, matches_any, EntryFilter, EntryKind, ExtractionGuard};
 
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::path::PathBuf;
+
+/// Applies permissions and a modification time, as recorded in an archive, to an
+/// extracted file or directory.
+fn apply_metadata(path: &Path, mode: Option<u32>, mtime: Option<u64>) -> Result<(), String> {
+    if let Some(mode) = mode {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            set_permissions(path, Permissions::from_mode(mode))
+                .map_err(|x| format!("Unable to set permissions of {:?}: {:?}", path, x))?;
+        }
+
+        #[cfg(not(unix))]
+        {
+            // Only the read-only flag can be represented here
+            if mode & 0o222 == 0 && path.is_file() {
+                let mut permissions = path
+                    .metadata()
+                    .map_err(|x| format!("Unable to read {:?}: {:?}", path, x))?
+                    .permissions();
+                permissions.set_readonly(true);
+
+                set_permissions(path, permissions)
+                    .map_err(|x| format!("Unable to set permissions of {:?}: {:?}", path, x))?;
+            }
+        }
+    }
+
+    if let Some(mtime) = mtime {
+        let time = FileTime::from_unix_time(mtime as i64, 0);
+
+        if let Err(v) = set_file_times(path, time, time) {
+            warn!("Unable to set modification time of {:?}: {:?}", path, v);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if the path is a directory, without following symlinks.
+fn is_real_dir(path: &Path) -> bool {
+    symlink_metadata(path)
+        .map(|x| x.file_type().is_dir())
+        .unwrap_or(false)
+}
+
+/// Creates a directory and its parents, recording which of these didn't exist before.
+fn create_dirs(dir: &Path, created: &mut Vec<PathBuf>) -> Result<(), String> {
+    let mut missing = Vec::new();
+
+    let mut current = Some(dir);
+    while let Some(v) = current {
+        if v.exists() {
+            break;
+        }
+
+        missing.push(v.to_path_buf());
+        current = v.parent();
+    }
+
+    create_dir_all(dir).map_err(|x| format!("Unable to create dir {:?}: {:?}", dir, x))?;
+
+    created.extend(missing.into_iter().rev());
+
+    Ok(())
+}
+
+/// Moves the files of an existing install out of the way, into the backup directory.
+pub fn back_up_files(
+    install_path: &Path,
+    backup_path: &Path,
+    files: &[String],
+) -> Result<(), String> {
+    let mut created = Vec::new();
+
+    for file in files {
+        let source = install_path.join(file);
+
+        // Directories are left in place, as the new version probably uses them too
+        if symlink_metadata(&source).is_err() || is_real_dir(&source) {
+            continue;
+        }
+
+        let target = backup_path.join(file);
+
+        if let Some(parent) = target.parent() {
+            create_dirs(parent, &mut created)?;
+        }
+
+        rename(&source, &target).map_err(|x| format!("Unable to back up {:?}: {:?}", source, x))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the journal which lists the files placed by an install of a
+/// package, given the path of its backup directory.
+fn journal_path(backup_path: &Path) -> PathBuf {
+    let mut path = backup_path.as_os_str().to_owned();
+    path.push(".placed");
+    PathBuf::from(path)
+}
+
+/// Moves extracted files from the staging directory into the install directory,
+/// recording everything which was placed there (both in memory and in the journal).
+fn place_files(
+    install_path: &Path,
+    staging_path: &Path,
+    files: &[String],
+    placed: &mut Vec<PathBuf>,
+    journal: &mut Write,
+) -> Result<(), String> {
+    for file in files {
+        let source = staging_path.join(file);
+        let target = install_path.join(file);
+
+        let first_new = placed.len();
+
+        if is_real_dir(&source) {
+            create_dirs(&target, placed)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                create_dirs(parent, placed)?;
+            }
+
+            if symlink_metadata(&target).is_ok() {
+                return Err(format!("{:?} already exists", target));
+            }
+
+            rename(&source, &target)
+                .map_err(|x| format!("Unable to move {:?}: {:?}", source, x))?;
+
+            placed.push(target);
+        }
+
+        for path in &placed[first_new..] {
+            writeln!(journal, "{}", path.to_string_lossy())
+                .map_err(|x| format!("Unable to write install journal: {:?}", x))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves a single backed up file back into place.
+fn restore_file(source: &Path, target: &Path) -> Result<(), String> {
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+    }
+
+    if symlink_metadata(target).is_ok() && !is_real_dir(target) {
+        remove_file(target).map_err(|x| format!("Unable to remove {:?}: {:?}", target, x))?;
+    }
+
+    rename(source, target).map_err(|x| format!("Unable to restore {:?}: {:?}", target, x))
+}
+
+/// Moves backed up files into the install directory, replacing anything in their way.
+fn restore_backup(backup_path: &Path, install_path: &Path) -> Result<(), String> {
+    if !backup_path.exists() {
+        return Ok(());
+    }
+
+    let mut error = None;
+    let mut pending = vec![backup_path.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries =
+            read_dir(&dir).map_err(|x| format!("Failed to read dir {:?}: {:?}", dir, x))?;
+
+        for entry in entries {
+            let source = entry
+                .map_err(|x| format!("Failed to read dir {:?}: {:?}", dir, x))?
+                .path();
+
+            if is_real_dir(&source) {
+                pending.push(source);
+                continue;
+            }
+
+            let relative = match source.strip_prefix(backup_path) {
+                Ok(v) => v.to_path_buf(),
+                Err(_) => continue,
+            };
+
+            if let Err(v) = restore_file(&source, &install_path.join(&relative)) {
+                error!("{}", v);
+                error = error.or(Some(v));
+            }
+        }
+    }
+
+    if let Some(v) = error {
+        return Err(v);
+    }
+
+    remove_dir_all(backup_path).map_err(|x| format!("Unable to remove backup: {:?}", x))
+}
+
+/// Removes files placed by an install, and puts the backed up files of the previous
+/// version back in their place.
+pub fn roll_back(
+    install_path: &Path,
+    backup_path: &Path,
+    placed: &[PathBuf],
+) -> Result<(), String> {
+    for path in placed.iter().rev() {
+        let result = if is_real_dir(path) {
+            remove_dir(path)
+        } else {
+            remove_file(path)
+        };
+
+        if let Err(e) = result {
+            warn!("Unable to remove {:?}: {:?}", path, e);
+        }
+    }
+
+    restore_backup(backup_path, install_path)?;
+
+    let journal = journal_path(backup_path);
+    if journal.exists() {
+        remove_file(&journal).map_err(|x| format!("Unable to remove {:?}: {:?}", journal, x))?;
+    }
+
+    Ok(())
+}
+
+/// Rolls back an install which was interrupted before it could finish, using the
+/// journal of files it had placed.
+pub fn recover_interrupted(install_path: &Path, backup_path: &Path) -> Result<(), String> {
+    let journal = journal_path(backup_path);
+
+    if !journal.exists() && !backup_path.exists() {
+        return Ok(());
+    }
+
+    warn!(
+        "Restoring files from an interrupted install in {:?}",
+        backup_path
+    );
+
+    let mut placed = Vec::new();
+
+    if journal.exists() {
+        let file =
+            File::open(&journal).map_err(|x| format!("Unable to open {:?}: {:?}", journal, x))?;
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|x| format!("Unable to read {:?}: {:?}", journal, x))?;
+
+            if !line.is_empty() {
+                placed.push(PathBuf::from(line));
+            }
+        }
+    }
+
+    roll_back(install_path, backup_path, &placed)
+}
+
+/// Replaces the files of an existing install with staged files. If this fails partway,
+/// the original files are put back. Otherwise, the original files are kept in the
+/// backup directory until `finish_swap` is called, so that `roll_back` can still undo
+/// the swap if a later step fails.
+fn swap_files(
+    install_path: &Path,
+    staging_path: &Path,
+    backup_path: &Path,
+    old_files: &[String],
+    new_files: &[String],
+    placed: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let journal = journal_path(backup_path);
+
+    if let Some(parent) = journal.parent() {
+        create_dir_all(parent).map_err(|x| format!("Unable to create dir: {:?}", x))?;
+    }
+
+    let result = File::create(&journal)
+        .map_err(|x| format!("Unable to create install journal: {:?}", x))
+        .and_then(|mut journal| {
+            back_up_files(install_path, backup_path, old_files)?;
+            place_files(install_path, staging_path, new_files, placed, &mut journal)
+        });
+
+    if let Err(v) = result {
+        error!("Failed to replace files, rolling back: {}", v);
+
+        if let Err(e) = roll_back(install_path, backup_path, placed) {
+            return Err(format!(
+                "{} (restoring the previous files also failed: {})",
+                v, e
+            ));
+        }
+
+        return Err(v);
+    }
+
+    Ok(())
+}
+
+/// Discards the backup of a previous version once its replacement has been recorded,
+/// removing directories which only the previous version used.
+pub fn finish_swap(
+    install_path: &Path,
+    backup_path: &Path,
+    old_files: &[String],
+    new_files: &[String],
+) {
+    for file in old_files.iter().rev() {
+        let path = install_path.join(file);
+
+        if !new_files.contains(file) && is_real_dir(&path) && remove_dir(&path).is_ok() {
+            info!("Removed dir: {:?}", path);
+        }
+    }
+
+    if backup_path.exists() {
+        if let Err(v) = remove_dir_all(backup_path) {
+            warn!("Unable to remove backup of previous files: {:?}", v);
+        }
+    }
+
+    let journal = journal_path(backup_path);
+    if journal.exists() {
+        if let Err(v) = remove_file(&journal) {
+            warn!("Unable to remove install journal: {:?}", v);
+        }
+    }
+}
+
+/// Hashes the config files of a staged package. Any which the user has changed since
+/// the previous version was installed are kept, with the new default staged alongside
+/// them instead.
+fn preserve_config_files(
+    install_path: &Path,
+    staging_path: &Path,
+    package: &PackageDescription,
+    previous: Option<&LocalInstallation>,
+    unchanged: &[String],
+    installed_files: &mut Vec<String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut hashes = HashMap::new();
+
+    if package.config_files.is_empty() {
+        return Ok(hashes);
+    }
+
+    let patterns = package
+        .config_files
+        .iter()
+        .map(|x| Pattern::new(x).map_err(|v| format!("Invalid pattern {:?}: {:?}", x, v)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut files = Vec::with_capacity(installed_files.len());
+
+    for file in installed_files.drain(..) {
+        let staged = staging_path.join(&file);
+
+        // Patterns always use forward slashes, regardless of platform
+        let name = file.replace('\\', "/");
+
+        let is_config = matches_any(&patterns, &name)
+            && symlink_metadata(&staged)
+                .map(|x| x.file_type().is_file())
+                .unwrap_or(false);
+
+        if !is_config {
+            files.push(file);
+            continue;
+        }
+
+        // Files carried over by a delta are the user's copy, so the original is unchanged
+        if unchanged.contains(&file) {
+            if let Some(hash) = previous.and_then(|x| x.file_hashes.get(&file)) {
+                hashes.insert(file.clone(), hash.clone());
+            }
+
+            files.push(file);
+            continue;
+        }
+
+        let new_hash = sha256_file(&staged)?;
+
+        let current = install_path.join(&file);
+        let modified = match previous {
+            Some(previous) if current.is_file() => {
+                let current_hash = sha256_file(&current)?;
+
+                // Without a record of the original, only identical files are safe to replace
+                let original = previous.file_hashes.get(&file).unwrap_or(&new_hash);
+
+                &current_hash != original && current_hash != new_hash
+            }
+            _ => false,
+        };
+
+        hashes.insert(file.clone(), new_hash);
+
+        if !modified {
+            files.push(file);
+            continue;
+        }
+
+        let default_file = format!("{}.new", file);
+        let default_path = staging_path.join(&default_file);
+
+        if symlink_metadata(&default_path).is_ok() {
+            return Err(format!("Package already contains {:?}", default_file));
+        }
+
+        info!(
+            "Keeping modified config file {:?}, writing the new default to {:?}",
+            file, default_file
+        );
+
+        rename(&staged, &default_path)
+            .map_err(|x| format!("Unable to stage {:?}: {:?}", default_file, x))?;
+        copy(&current, &staged).map_err(|x| format!("Unable to stage {:?}: {:?}", file, x))?;
+
+        files.push(file);
+        files.push(default_file);
+    }
+
+    *installed_files = files;
+
+    Ok(hashes)
+}
+
+/// Removes shortcuts which aren't in the list of shortcuts to keep.
+fn remove_shortcuts(install_path: &Path, shortcuts: &[String], keep: &[String]) {
+    for shortcut in shortcuts {
+        if keep.contains(shortcut) {
+            continue;
+        }
+
+        let path = install_path.join(shortcut);
+        info!("Deleting shortcut {:?}", path);
+
+        if let Err(v) = remove_file(&path) {
+            warn!("Failed to delete shortcut: {:?}", v);
+        }
+    }
+}
 
 pub struct InstallPackageTask {
     pub name: String,
@@ -43,7 +511,7 @@ impl Task for InstallPackageTask {
 
         let path = context
             .install_path
-            .as_ref()
+            .clone()
             .log_expect("No install path specified");
 
         let mut installed_files = Vec::new();
@@ -75,19 +543,117 @@ impl Task for InstallPackageTask {
             _ => return Err("Unexpected shortcuts param type to install package".to_string()),
         };
 
-        // Ignore input from the uninstaller - no useful information passed
-        input.pop();
+        // The pre-uninstall hook of the version being replaced has nothing to pass on
+        input
+            .pop()
+            .log_expect("Should have input from pre-uninstall hook!");
+
+        // The previous install is only replaced once the new version is ready
+        let previous = context
+            .database
+            .packages
+            .iter()
+            .find(|x| x.name == self.name)
+            .cloned();
+
+        let pinned = match context.selections.get(&self.name) {
+            Some(selection) => selection.pin(
+                previous
+                    .as_ref()
+                    .and_then(|x| x.pinned.as_ref().map(|x| x.as_str())),
+            ),
+            None => previous.as_ref().and_then(|x| x.pinned.clone()),
+        };
 
         // Grab data from the resolver
         let data = input.pop().log_expect("Should have input from resolver!");
-        let (version, file, data) = match data {
-            TaskParamType::FileContents(version, file, data) => (version, file, data),
-            _ => return Err("Unexpected file contents param type to install package".to_string()),
+        let (release, file, download_path, unchanged) = match data {
+            TaskParamType::DownloadedFile(release, file, path, unchanged) => {
+                (release, file, path, unchanged)
+            }
+            _ => return Err("Unexpected downloaded file param type to install package".to_string()),
+        };
+
+        // Deltas of raw files are staged like any other package
+        let mut archive = match package.raw {
+            Some(ref raw) if !download_path.is_dir() => archives::read_raw_file(
+                &download_path,
+                Path::new(&raw.relative_path),
+                raw.executable,
+            )?,
+            _ => archives::read_archive(&file.name, &download_path)?,
+        };
+
+        // Staged directories have already been checked, so only limit archives
+        let compressed_size = if download_path.is_dir() {
+            None
+        } else {
+            download_path.metadata().ok().map(|x| x.len())
+        };
+
+        let limits = context
+            .config
+            .as_ref()
+            .log_expect("Should have packages by now")
+            .extraction_limits
+            .clone();
+
+        let mut guard = ExtractionGuard::new(limits, compressed_size);
+
+        // Packages are extracted next to the install, so they can be moved into place
+        let cache_dir = context
+            .get_cache_dir()
+            .log_expect("Should have an install path by now");
+        let dir_name = self.name.replace(|c: char| c == '/' || c == '\\', "_");
+        let staging_path = context
+            .get_staging_dir(&self.name)
+            .log_expect("Should have an install path by now");
+        let backup_path = cache_dir.join("backup").join(&dir_name);
+
+        // Files left behind by an interrupted install still belong to the previous version
+        recover_interrupted(&path, &backup_path)?;
+
+        if staging_path.exists() {
+            remove_dir_all(&staging_path)
+                .map_err(|x| format!("Unable to clean up staged files: {:?}", x))?;
+        }
+
+        create_dir_all(&staging_path)
+            .map_err(|x| format!("Unable to create staging dir: {:?}", x))?;
+
+        // Staged directories and raw files are already laid out as they are installed
+        let filter = if download_path.is_dir() || package.raw.is_some() {
+            None
+        } else {
+            Some(EntryFilter::new(&package)?)
+        };
+
+        // Special bits from a downloaded archive could grant privileges when run as root
+        let mode_mask = if package.allow_special_modes {
+            0o7777
+        } else {
+            0o777
         };
 
-        let mut archive = archives::read_archive(&file.name, data.as_slice())?;
+        // Directory permissions are applied last, in case they prevent writing files
+        let mut directory_modes = Vec::new();
+
+        let result = archive.for_each(&mut |i, archive_size, entry, file| {
+            guard.add_entry()?;
+
+            let mode = entry.mode.map(|x| x & mode_mask);
+
+            let filename = match filter {
+                Some(ref filter) => match filter.apply(&entry.path) {
+                    Some(v) => v,
+                    None => {
+                        info!("Skipping {:?}", entry.path);
+                        return Ok(());
+                    }
+                },
+                None => entry.path,
+            };
 
-        archive.for_each(&mut |i, archive_size, filename, mut file| {
             let string_name = filename
                 .to_str()
                 .ok_or("Unable to get str from file name")?
@@ -108,6 +674,15 @@ impl Task for InstallPackageTask {
                 }
             }
 
+            let target_path = staging_path.join(&filename);
+
+            let link = match entry.kind {
+                EntryKind::Symlink(ref target) => Some(target.as_path()),
+                _ => None,
+            };
+
+            check_within(&staging_path, &target_path, link)?;
+
             // Ensure that parent directories exist
             let mut parent_dir: &Path = &filename;
             while let Some(v) = parent_dir.parent() {
@@ -127,15 +702,81 @@ impl Task for InstallPackageTask {
                     installed_files.push(string_name);
                 }
 
-                match create_dir_all(path.join(&parent_dir)) {
+                match create_dir_all(staging_path.join(&parent_dir)) {
                     Ok(v) => v,
                     Err(v) => return Err(format!("Unable to create dir: {:?}", v)),
                 }
             }
 
-            // Create target file
-            let target_path = path.join(&filename);
+            // Hard links are extracted as a copy of their target
+            let mut linked_file;
+
+            let file: &mut Read = match entry.kind {
+                EntryKind::Directory => {
+                    info!("Creating dir: {:?}", string_name);
+
+                    if let Err(v) = create_dir_all(&target_path) {
+                        return Err(format!("Unable to create dir: {:?}", v));
+                    }
+
+                    if !installed_files.contains(&string_name) {
+                        installed_files.push(string_name);
+                    }
+
+                    directory_modes.push((filename.clone(), mode, entry.mtime));
+
+                    return Ok(());
+                }
+                EntryKind::Symlink(ref target) => {
+                    info!("Creating symlink: {:?} -> {:?}", string_name, target);
+
+                    if !installed_files.contains(&string_name) {
+                        installed_files.push(string_name);
+                    }
+
+                    return create_symlink(target, &target_path);
+                }
+                EntryKind::HardLink(ref target) => {
+                    info!("Copying hard link: {:?} -> {:?}", string_name, target);
+
+                    let linked_name = match filter {
+                        Some(ref filter) => filter.apply(target),
+                        None => Some(target.clone()),
+                    };
 
+                    let linked_path = match linked_name {
+                        Some(v) => staging_path.join(v),
+                        None => {
+                            return Err(format!(
+                                "Hard link {:?} points to a skipped file: {:?}",
+                                string_name, target
+                            ))
+                        }
+                    };
+
+                    check_within(&staging_path, &linked_path, None)?;
+
+                    // Only files extracted earlier can be linked to, as with tar itself
+                    let is_file = symlink_metadata(&linked_path)
+                        .map(|x| x.file_type().is_file())
+                        .unwrap_or(false);
+
+                    if !is_file {
+                        return Err(format!(
+                            "Hard link {:?} doesn't point to an extracted file: {:?}",
+                            string_name, target
+                        ));
+                    }
+
+                    linked_file = File::open(&linked_path)
+                        .map_err(|x| format!("Unable to open {:?}: {:?}", linked_path, x))?;
+
+                    &mut linked_file
+                }
+                EntryKind::File => file,
+            };
+
+            // Create target file
             info!("Creating file: {:?}", string_name);
 
             if !installed_files.contains(&string_name) {
@@ -152,28 +793,150 @@ impl Task for InstallPackageTask {
                 file_metadata.mode(0o770);
             }
 
-            let mut target_file = match file_metadata.open(target_path) {
+            let mut target_file = match file_metadata.open(&target_path) {
                 Ok(v) => v,
                 Err(v) => return Err(format!("Unable to open file handle: {:?}", v)),
             };
 
             // Cross the streams
-            match copy(&mut file, &mut target_file) {
-                Ok(v) => v,
-                Err(v) => return Err(format!("Unable to write to file: {:?}", v)),
-            };
+            guard.copy(file, &mut target_file)?;
+
+            drop(target_file);
+
+            apply_metadata(&target_path, mode, entry.mtime)
+        });
 
-            Ok(())
-        })?;
+        // Close the archive before removing it
+        drop(archive);
 
-        // Save metadata about this package
+        let old_files = previous
+            .as_ref()
+            .map(|x| x.files.clone())
+            .unwrap_or_default();
+        let old_shortcuts = previous
+            .as_ref()
+            .map(|x| x.shortcuts.clone())
+            .unwrap_or_default();
+
+        let mut file_hashes = HashMap::new();
+        let mut placed = Vec::new();
+
+        let result = result.and_then(|_| {
+            messenger(&TaskMessage::DisplayMessage(
+                &format!("Replacing files of package {:?}...", self.name),
+                1.0,
+            ));
+
+            file_hashes = preserve_config_files(
+                &path,
+                &staging_path,
+                &package,
+                previous.as_ref(),
+                &unchanged,
+                &mut installed_files,
+            )?;
+
+            swap_files(
+                &path,
+                &staging_path,
+                &backup_path,
+                &old_files,
+                &installed_files,
+                &mut placed,
+            )
+        });
+
+        if staging_path.exists() {
+            if let Err(v) = remove_dir_all(&staging_path) {
+                warn!("Unable to clean up staged files: {:?}", v);
+            }
+        }
+
+        if let Err(v) = result {
+            remove_shortcuts(&path, &shortcuts, &old_shortcuts);
+            return Err(v);
+        }
+
+        let old_version = previous.as_ref().map(|x| x.version.to_string());
+        let new_version = release.version.to_string();
+
+        // Save metadata about this package. The previous version is kept until this has
+        // been written (and the post-install hook has succeeded), so that it can be
+        // restored if anything fails.
+        context.database.packages.retain(|x| x.name != package.name);
         context.database.packages.push(LocalInstallation {
             name: package.name.to_owned(),
-            version,
-            shortcuts,
-            files: installed_files,
+            version: release.version,
+            tag: release.tag,
+            release_name: release.name,
+            channel: release.channel,
+            pinned,
+            shortcuts: shortcuts.clone(),
+            files: installed_files.clone(),
+            file_hashes,
         });
 
+        let result = run_hook(
+            context,
+            &self.name,
+            HookKind::PostInstall,
+            old_version,
+            Some(new_version),
+            messenger,
+        )
+        .and_then(|_| context.save_database());
+
+        if let Err(v) = result {
+            error!(
+                "Failed to finish install of {:?}, rolling back: {}",
+                self.name, v
+            );
+
+            context.database.packages.retain(|x| x.name != package.name);
+            context.database.packages.extend(previous);
+
+            if let Err(e) = context.save_database() {
+                warn!("Unable to restore the previous database: {}", e);
+            }
+
+            remove_shortcuts(&path, &shortcuts, &old_shortcuts);
+
+            if let Err(e) = roll_back(&path, &backup_path, &placed) {
+                return Err(format!(
+                    "{} (restoring the previous files also failed: {})",
+                    v, e
+                ));
+            }
+
+            return Err(v);
+        }
+
+        finish_swap(&path, &backup_path, &old_files, &installed_files);
+
+        remove_shortcuts(&path, &old_shortcuts, &shortcuts);
+
+        // Deepest directories first, so that parents are still writable
+        for (dir, mode, mtime) in directory_modes.into_iter().rev() {
+            // Ensure that the installer can still modify this directory later
+            if let Err(v) = apply_metadata(&path.join(&dir), mode.map(|x| x | 0o700), mtime) {
+                warn!("{}", v);
+            }
+        }
+
+        // Deltas are staged as a directory, rather than an archive
+        let cleanup = if download_path.is_dir() {
+            remove_dir_all(&download_path)
+        } else {
+            remove_file(&download_path)
+        };
+
+        if let Err(v) = cleanup {
+            warn!(
+                "Unable to remove downloaded file {:?}: {:?}",
+                download_path, v
+            );
+        }
+
         messenger(&TaskMessage::PackageInstalled);
 
         Ok(TaskParamType::None)
@@ -183,15 +946,16 @@ impl Task for InstallPackageTask {
         vec![
             TaskDependency::build(
                 TaskOrdering::Pre,
-                Box::new(DownloadPackageTask {
+                Box::new(RunHookTask {
                     name: self.name.clone(),
+                    kind: HookKind::PreInstall,
                 }),
             ),
             TaskDependency::build(
                 TaskOrdering::Pre,
-                Box::new(UninstallPackageTask {
+                Box::new(RunHookTask {
                     name: self.name.clone(),
-                    optional: true,
+                    kind: HookKind::PreUninstall,
                 }),
             ),
             TaskDependency::build(
@@ -200,7 +964,6 @@ impl Task for InstallPackageTask {
                     name: self.name.clone(),
                 }),
             ),
-            TaskDependency::build(TaskOrdering::Post, Box::new(SaveDatabaseTask {})),
         ]
     }
 
@@ -208,3 +971,744 @@ impl Task for InstallPackageTask {
         format!("InstallPackageTask (for {:?})", self.name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::read_to_string;
+
+    /// Creates an empty directory for a test to work in.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("install-test-{}-{}", name, ::std::process::id()));
+        if path.exists() {
+            remove_dir_all(&path).unwrap();
+        }
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn write(path: &Path, contents: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        read_to_string(path).unwrap()
+    }
+
+    fn strings(files: &[&str]) -> Vec<String> {
+        files.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// Sets up an install of an old version, and a staged new version of it. Returns the
+    /// install, staging and backup paths, and the files of each version.
+    fn versions(name: &str) -> (PathBuf, PathBuf, PathBuf, Vec<String>, Vec<String>) {
+        let root = scratch_dir(name);
+        let install = root.join("install");
+        let staging = root.join("staging");
+        let backup = root.join("backup").join("package");
+
+        write(&install.join("a.txt"), "old a");
+        write(&install.join("dir/b.txt"), "old b");
+        write(&install.join("old/c.txt"), "old c");
+        write(&install.join("user.txt"), "not installed");
+
+        write(&staging.join("a.txt"), "new a");
+        write(&staging.join("dir/b.txt"), "new b");
+        write(&staging.join("new/d.txt"), "new d");
+
+        let old_files = strings(&["a.txt", "dir", "dir/b.txt", "old", "old/c.txt"]);
+        let new_files = strings(&["a.txt", "dir", "dir/b.txt", "new", "new/d.txt"]);
+
+        (install, staging, backup, old_files, new_files)
+    }
+
+    /// Checks that an install directory contains the old version as set up by `versions`.
+    fn assert_old_version(install: &Path, backup: &Path) {
+        assert_eq!(read(&install.join("a.txt")), "old a");
+        assert_eq!(read(&install.join("dir/b.txt")), "old b");
+        assert_eq!(read(&install.join("old/c.txt")), "old c");
+        assert_eq!(read(&install.join("user.txt")), "not installed");
+        assert!(!install.join("new").exists());
+        assert!(!backup.exists());
+        assert!(!journal_path(backup).exists());
+    }
+
+    #[test]
+    fn swap_files_keeps_backup_until_finished() {
+        let (install, staging, backup, old_files, new_files) = versions("swap");
+        let mut placed = Vec::new();
+
+        swap_files(
+            &install,
+            &staging,
+            &backup,
+            &old_files,
+            &new_files,
+            &mut placed,
+        )
+        .unwrap();
+
+        assert_eq!(read(&install.join("a.txt")), "new a");
+        assert_eq!(read(&install.join("dir/b.txt")), "new b");
+        assert_eq!(read(&install.join("new/d.txt")), "new d");
+        assert!(!install.join("old/c.txt").exists());
+        assert_eq!(read(&backup.join("a.txt")), "old a");
+        assert_eq!(read(&backup.join("old/c.txt")), "old c");
+        assert!(placed.contains(&install.join("new")));
+        assert!(!placed.contains(&install.join("dir")));
+
+        finish_swap(&install, &backup, &old_files, &new_files);
+
+        assert!(!install.join("old").exists());
+        assert!(install.join("dir").exists());
+        assert_eq!(read(&install.join("user.txt")), "not installed");
+        assert!(!backup.exists());
+        assert!(!journal_path(&backup).exists());
+    }
+
+    #[test]
+    fn roll_back_restores_previous_version() {
+        let (install, staging, backup, old_files, new_files) = versions("roll-back");
+        let mut placed = Vec::new();
+
+        swap_files(
+            &install,
+            &staging,
+            &backup,
+            &old_files,
+            &new_files,
+            &mut placed,
+        )
+        .unwrap();
+        roll_back(&install, &backup, &placed).unwrap();
+
+        assert_old_version(&install, &backup);
+    }
+
+    #[test]
+    fn swap_files_rolls_back_when_placing_fails() {
+        let (install, staging, backup, old_files, mut new_files) = versions("swap-fail");
+
+        // Files which weren't installed by the previous version are never replaced
+        write(&staging.join("user.txt"), "new user");
+        new_files.push("user.txt".to_string());
+
+        let mut placed = Vec::new();
+        let result = swap_files(
+            &install,
+            &staging,
+            &backup,
+            &old_files,
+            &new_files,
+            &mut placed,
+        );
+
+        assert!(result.is_err());
+        assert_old_version(&install, &backup);
+    }
+
+    #[test]
+    fn recover_interrupted_removes_orphaned_files() {
+        let (install, staging, backup, old_files, new_files) = versions("recover");
+
+        // As if the installer exited before the swap was finished or rolled back
+        swap_files(
+            &install,
+            &staging,
+            &backup,
+            &old_files,
+            &new_files,
+            &mut Vec::new(),
+        )
+        .unwrap();
+        recover_interrupted(&install, &backup).unwrap();
+
+        assert_old_version(&install, &backup);
+    }
+
+    #[test]
+    fn recover_interrupted_without_journal_does_nothing() {
+        let (install, _, backup, _, _) = versions("recover-none");
+
+        recover_interrupted(&install, &backup).unwrap();
+
+        assert_old_version(&install, &backup);
+    }
+
+    fn hash(contents: &str) -> String {
+        ::checksum::sha256_reader(&mut contents.as_bytes()).unwrap()
+    }
+
+    fn config_package(patterns: &[&str]) -> PackageDescription {
+        ::toml::from_str(&format!(
+            "name = \"package\"\n\
+             description = \"\"\n\
+             config_files = {:?}\n\
+             [source]\n\
+             name = \"github\"\n\
+             match = \".*\"\n\
+             config = {{ repo = \"a/b\" }}\n",
+            patterns
+        ))
+        .unwrap()
+    }
+
+    fn installation(file_hashes: &[(&str, &str)]) -> LocalInstallation {
+        LocalInstallation {
+            name: "package".to_string(),
+            version: ::sources::types::Version::new_number(1),
+            tag: None,
+            release_name: None,
+            channel: None,
+            pinned: None,
+            files: Vec::new(),
+            shortcuts: Vec::new(),
+            file_hashes: file_hashes
+                .iter()
+                .map(|&(file, contents)| (file.to_string(), hash(contents)))
+                .collect(),
+        }
+    }
+
+    /// Sets up an installed and a staged copy of "app.conf", returning the install and
+    /// staging paths.
+    fn config_file(name: &str, installed: Option<&str>, staged: &str) -> (PathBuf, PathBuf) {
+        let root = scratch_dir(name);
+        let install = root.join("install");
+        let staging = root.join("staging");
+
+        if let Some(installed) = installed {
+            write(&install.join("app.conf"), installed);
+        }
+        write(&staging.join("app.conf"), staged);
+
+        (install, staging)
+    }
+
+    fn preserve(
+        install: &Path,
+        staging: &Path,
+        previous: Option<&LocalInstallation>,
+        unchanged: &[&str],
+    ) -> (Vec<String>, HashMap<String, String>) {
+        let mut files = strings(&["app.conf"]);
+        let hashes = preserve_config_files(
+            install,
+            staging,
+            &config_package(&["*.conf"]),
+            previous,
+            &strings(unchanged),
+            &mut files,
+        )
+        .unwrap();
+
+        (files, hashes)
+    }
+
+    #[test]
+    fn preserve_config_files_records_defaults_on_fresh_install() {
+        let (install, staging) = config_file("config-fresh", None, "default");
+
+        let (files, hashes) = preserve(&install, &staging, None, &[]);
+
+        assert_eq!(files, strings(&["app.conf"]));
+        assert_eq!(hashes.get("app.conf"), Some(&hash("default")));
+        assert_eq!(read(&staging.join("app.conf")), "default");
+    }
+
+    #[test]
+    fn preserve_config_files_replaces_unmodified_files() {
+        let (install, staging) = config_file("config-unmodified", Some("old"), "new");
+        let previous = installation(&[("app.conf", "old")]);
+
+        let (files, hashes) = preserve(&install, &staging, Some(&previous), &[]);
+
+        assert_eq!(files, strings(&["app.conf"]));
+        assert_eq!(hashes.get("app.conf"), Some(&hash("new")));
+        assert_eq!(read(&staging.join("app.conf")), "new");
+    }
+
+    #[test]
+    fn preserve_config_files_keeps_modified_files() {
+        let (install, staging) = config_file("config-modified", Some("edited"), "new");
+        let previous = installation(&[("app.conf", "old")]);
+
+        let (files, hashes) = preserve(&install, &staging, Some(&previous), &[]);
+
+        assert_eq!(files, strings(&["app.conf", "app.conf.new"]));
+        assert_eq!(hashes.get("app.conf"), Some(&hash("new")));
+        assert_eq!(read(&staging.join("app.conf")), "edited");
+        assert_eq!(read(&staging.join("app.conf.new")), "new");
+    }
+
+    #[test]
+    fn preserve_config_files_replaces_edits_matching_the_new_default() {
+        let (install, staging) = config_file("config-same", Some("new"), "new");
+        let previous = installation(&[("app.conf", "old")]);
+
+        let (files, _) = preserve(&install, &staging, Some(&previous), &[]);
+
+        assert_eq!(files, strings(&["app.conf"]));
+    }
+
+    #[test]
+    fn preserve_config_files_keeps_files_without_a_recorded_default() {
+        let (install, staging) = config_file("config-unrecorded", Some("edited"), "new");
+        let previous = installation(&[]);
+
+        let (files, _) = preserve(&install, &staging, Some(&previous), &[]);
+
+        assert_eq!(files, strings(&["app.conf", "app.conf.new"]));
+        assert_eq!(read(&staging.join("app.conf")), "edited");
+    }
+
+    #[test]
+    fn preserve_config_files_carries_defaults_over_for_unchanged_files() {
+        // A delta which doesn't touch the file carries the user's copy over as-is
+        let (install, staging) = config_file("config-unchanged", Some("edited"), "edited");
+        let previous = installation(&[("app.conf", "old")]);
+
+        let (files, hashes) = preserve(&install, &staging, Some(&previous), &["app.conf"]);
+
+        assert_eq!(files, strings(&["app.conf"]));
+        assert_eq!(hashes.get("app.conf"), Some(&hash("old")));
+        assert!(!staging.join("app.conf.new").exists());
+
+        let (_, hashes) = preserve(&install, &staging, Some(&installation(&[])), &["app.conf"]);
+        assert!(hashes.is_empty());
+    }
+
+    #[test]
+    fn preserve_config_files_rejects_packages_containing_new_files() {
+        let (install, staging) = config_file("config-conflict", Some("edited"), "new");
+        write(&staging.join("app.conf.new"), "packaged");
+        let previous = installation(&[("app.conf", "old")]);
+
+        let mut files = strings(&["app.conf", "app.conf.new"]);
+        let result = preserve_config_files(
+            &install,
+            &staging,
+            &config_package(&["*.conf"]),
+            Some(&previous),
+            &[],
+            &mut files,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(read(&staging.join("app.conf.new")), "packaged");
+    }
+
+    #[test]
+    fn preserve_config_files_only_matches_files() {
+        let root = scratch_dir("config-patterns");
+        let staging = root.join("staging");
+        write(&staging.join("app.conf"), "a");
+        write(&staging.join("etc/app.conf"), "b");
+        create_dir_all(staging.join("dir.conf")).unwrap();
+
+        let hashed = |patterns: &[&str]| {
+            let mut files = strings(&["app.conf", "etc", "etc/app.conf", "dir.conf"]);
+            let package = config_package(patterns);
+            let hashes =
+                preserve_config_files(&root, &staging, &package, None, &[], &mut files).unwrap();
+
+            let mut hashed = hashes.keys().cloned().collect::<Vec<_>>();
+            hashed.sort();
+            hashed
+        };
+
+        assert_eq!(hashed(&["*.conf"]), strings(&["app.conf"]));
+        assert_eq!(
+            hashed(&["**/*.conf"]),
+            strings(&["app.conf", "etc/app.conf"])
+        );
+        assert_eq!(hashed(&["etc/*"]), strings(&["etc/app.conf"]));
+    }
+
+    /// Builds an installer for a single package named "package", installed into a new
+    /// directory.
+    fn framework(name: &str, options: &str) -> InstallerFramework {
+        let attributes = ::config::BaseAttributes::from_toml_str(
+            "name = \"test\"\n\
+             target_url = \"\"\n",
+        )
+        .unwrap();
+
+        let config = ::config::Config::from_toml_str(&format!(
+            "installing_message = \"\"\n\
+             [[packages]]\n\
+             name = \"package\"\n\
+             description = \"\"\n\
+             strip_components = 1\n\
+             {}\n\
+             [packages.source]\n\
+             name = \"github\"\n\
+             match = \".*\"\n\
+             config = {{ repo = \"a/b\" }}\n",
+            options
+        ))
+        .unwrap();
+
+        let install = scratch_dir(name).join("install");
+        create_dir_all(&install).unwrap();
+
+        let mut context = InstallerFramework::new(attributes);
+        context.config = Some(config);
+        context.install_path = Some(install);
+        context
+    }
+
+    /// Writes a tar archive of files (or directories, without contents).
+    fn write_tar(path: &Path, entries: &[(&str, Option<&str>)]) {
+        write_tar_with_links(path, entries, &[]);
+    }
+
+    /// Writes a tar archive of files, followed by hard links to them.
+    fn write_tar_with_links(path: &Path, entries: &[(&str, Option<&str>)], links: &[(&str, &str)]) {
+        let mut builder = ::tar::Builder::new(File::create(path).unwrap());
+
+        for &(name, contents) in entries {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_path(name).unwrap();
+            header.set_mtime(0);
+
+            let data = contents.unwrap_or("").as_bytes();
+
+            match contents {
+                Some(_) => {
+                    header.set_entry_type(::tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                }
+                None => {
+                    header.set_entry_type(::tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                }
+            }
+
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+
+        for &(name, target) in links {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_path(name).unwrap();
+            header.set_link_name(target).unwrap();
+            header.set_entry_type(::tar::EntryType::Link);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, &b""[..]).unwrap();
+        }
+
+        builder.finish().unwrap();
+    }
+
+    /// Installs a version of "package" from a tar archive of the specified entries.
+    fn install(
+        context: &mut InstallerFramework,
+        version: u64,
+        entries: &[(&str, Option<&str>)],
+    ) -> Result<TaskParamType, String> {
+        let archive = context
+            .get_cache_dir()
+            .unwrap()
+            .join(format!("package-{}.tar", version));
+        create_dir_all(archive.parent().unwrap()).unwrap();
+        write_tar(&archive, entries);
+
+        install_archive(context, version, archive)
+    }
+
+    /// Installs a version of "package" from an existing archive.
+    fn install_archive(
+        context: &mut InstallerFramework,
+        version: u64,
+        archive: PathBuf,
+    ) -> Result<TaskParamType, String> {
+        let release = ::sources::types::Release {
+            version: ::sources::types::Version::new_number(version),
+            files: Vec::new(),
+            tag: None,
+            name: None,
+            prerelease: false,
+            channel: None,
+        };
+
+        let file = ::sources::types::File {
+            name: "package.tar".to_string(),
+            url: String::new(),
+            size: None,
+            sha256: None,
+            mirrors: Vec::new(),
+        };
+
+        let input = vec![
+            TaskParamType::DownloadedFile(release, file, archive, Vec::new()),
+            TaskParamType::None,
+            TaskParamType::GeneratedShortcuts(Vec::new()),
+        ];
+
+        let mut task = InstallPackageTask {
+            name: "package".to_string(),
+        };
+
+        task.execute(input, context, &|_| {})
+    }
+
+    const VERSION_1: &[(&str, Option<&str>)] = &[
+        ("package-1/", None),
+        ("package-1/a.txt", Some("1")),
+        ("package-1/old/b.txt", Some("1")),
+    ];
+
+    const VERSION_2: &[(&str, Option<&str>)] = &[
+        ("package-2/", None),
+        ("package-2/a.txt", Some("2")),
+        ("package-2/new/c.txt", Some("2")),
+    ];
+
+    /// Checks that the install directory, and its database, contain version 1.
+    fn assert_version_1(context: &InstallerFramework) {
+        let install = context.install_path.clone().unwrap();
+
+        assert_eq!(read(&install.join("a.txt")), "1");
+        assert_eq!(read(&install.join("old/b.txt")), "1");
+        assert!(!install.join("new").exists());
+
+        let saved =
+            InstallerFramework::new_with_db(context.base_attributes.clone(), &install).unwrap();
+        for database in &[&context.database, &saved.database] {
+            assert_eq!(database.packages.len(), 1);
+            assert_eq!(
+                database.packages[0].version,
+                ::sources::types::Version::new_number(1)
+            );
+        }
+    }
+
+    /// Checks that nothing is left behind in the cache after an install.
+    fn assert_cleaned_up(context: &InstallerFramework) {
+        let cache = context.get_cache_dir().unwrap();
+
+        assert!(!context.get_staging_dir("package").unwrap().exists());
+        assert!(!cache.join("backup").join("package").exists());
+        assert!(!cache.join("backup").join("package.placed").exists());
+    }
+
+    #[test]
+    fn execute_installs_archive() {
+        let mut context = framework("execute", "");
+
+        install(&mut context, 1, VERSION_1).unwrap();
+
+        assert_version_1(&context);
+        assert_cleaned_up(&context);
+        assert_eq!(
+            context.database.packages[0].files,
+            strings(&["a.txt", "old", "old/b.txt"])
+        );
+    }
+
+    #[test]
+    fn execute_replaces_previous_version() {
+        let mut context = framework("execute-update", "");
+        let install_path = context.install_path.clone().unwrap();
+
+        install(&mut context, 1, VERSION_1).unwrap();
+        install(&mut context, 2, VERSION_2).unwrap();
+
+        assert_eq!(read(&install_path.join("a.txt")), "2");
+        assert_eq!(read(&install_path.join("new/c.txt")), "2");
+        assert!(!install_path.join("old").exists());
+        assert_eq!(
+            context.database.packages[0].version,
+            ::sources::types::Version::new_number(2)
+        );
+        assert_cleaned_up(&context);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_rolls_back_when_post_install_hook_fails() {
+        // Hooks run in the install directory
+        let mut context = framework(
+            "execute-hook",
+            "hooks = { post_install = \"test ! -e fail\" }",
+        );
+        let install_path = context.install_path.clone().unwrap();
+
+        install(&mut context, 1, VERSION_1).unwrap();
+        write(&install_path.join("fail"), "");
+
+        assert!(install(&mut context, 2, VERSION_2).is_err());
+
+        assert_version_1(&context);
+        assert_cleaned_up(&context);
+    }
+
+    #[test]
+    fn execute_rolls_back_when_extraction_fails() {
+        let mut context = framework("execute-extract", "");
+
+        install(&mut context, 1, VERSION_1).unwrap();
+
+        // Only the first entry of each path can be created
+        let duplicate = &[
+            ("package-2/a.txt", Some("2")),
+            ("package-2/a.txt", Some("3")),
+        ];
+        assert!(install(&mut context, 2, duplicate).is_err());
+
+        assert_version_1(&context);
+        assert_cleaned_up(&context);
+    }
+
+    #[test]
+    fn execute_recovers_interrupted_install() {
+        let mut context = framework("execute-recover", "");
+        let install_path = context.install_path.clone().unwrap();
+        let cache = context.get_cache_dir().unwrap();
+
+        install(&mut context, 1, VERSION_1).unwrap();
+
+        // As if the installer exited partway through replacing version 1
+        let staging = cache.join("interrupted");
+        write(&staging.join("a.txt"), "interrupted");
+        write(&staging.join("orphan.txt"), "interrupted");
+        swap_files(
+            &install_path,
+            &staging,
+            &cache.join("backup").join("package"),
+            &context.database.packages[0].files,
+            &strings(&["a.txt", "orphan.txt"]),
+            &mut Vec::new(),
+        )
+        .unwrap();
+
+        install(&mut context, 2, VERSION_2).unwrap();
+
+        assert_eq!(read(&install_path.join("a.txt")), "2");
+        assert!(!install_path.join("orphan.txt").exists());
+        assert!(!install_path.join("old").exists());
+        assert_cleaned_up(&context);
+    }
+
+    /// Installs an executable with the specified mode, returning the mode it ends up with.
+    #[cfg(unix)]
+    fn installed_mode(name: &str, options: &str, mode: u32) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut context = framework(name, options);
+        let archive = context.get_cache_dir().unwrap().join("package.tar");
+        create_dir_all(archive.parent().unwrap()).unwrap();
+
+        let mut builder = ::tar::Builder::new(File::create(&archive).unwrap());
+        let mut header = ::tar::Header::new_gnu();
+        header.set_path("package/run").unwrap();
+        header.set_entry_type(::tar::EntryType::Regular);
+        header.set_mode(mode);
+        header.set_size(0);
+        header.set_cksum();
+        builder.append(&header, &[][..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        install_archive(&mut context, 1, archive).unwrap();
+
+        let path = context.install_path.unwrap().join("run");
+        path.metadata().unwrap().permissions().mode() & 0o7777
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_clears_special_modes() {
+        assert_eq!(installed_mode("mode", "", 0o6755), 0o755);
+        assert_eq!(installed_mode("mode-sticky", "", 0o1644), 0o644);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_keeps_special_modes_when_allowed() {
+        let mode = installed_mode("mode-allowed", "allow_special_modes = true", 0o4755);
+
+        assert_eq!(mode, 0o4755);
+    }
+
+    /// Installs version 1 of "package", with an extra hard link to the specified target.
+    fn install_hard_link(
+        name: &str,
+        options: &str,
+        target: &str,
+    ) -> (InstallerFramework, Result<TaskParamType, String>) {
+        let mut context = framework(name, options);
+
+        let archive = context.get_cache_dir().unwrap().join("package-1.tar");
+        create_dir_all(archive.parent().unwrap()).unwrap();
+        write_tar_with_links(&archive, VERSION_1, &[("package-1/link.txt", target)]);
+
+        let result = install_archive(&mut context, 1, archive);
+        (context, result)
+    }
+
+    #[test]
+    fn execute_copies_hard_linked_files() {
+        let (context, result) = install_hard_link("hard-link", "", "package-1/a.txt");
+        let install = context.install_path.clone().unwrap();
+
+        assert!(result.is_ok(), "{:?}", result.err());
+        assert_eq!(read(&install.join("link.txt")), "1");
+        assert!(context.database.packages[0]
+            .files
+            .contains(&"link.txt".to_string()));
+
+        // Changing one of the files mustn't change the other
+        write(&install.join("link.txt"), "changed");
+        assert_eq!(read(&install.join("a.txt")), "1");
+    }
+
+    #[test]
+    fn execute_rejects_hard_links_to_skipped_files() {
+        let options = "exclude = [\"a.txt\"]";
+        let (context, result) = install_hard_link("hard-link-skipped", options, "package-1/a.txt");
+
+        assert!(result.is_err());
+        assert!(!context
+            .install_path
+            .clone()
+            .unwrap()
+            .join("link.txt")
+            .exists());
+        assert_cleaned_up(&context);
+    }
+
+    #[test]
+    fn execute_rejects_hard_links_to_missing_files() {
+        let (_, result) = install_hard_link("hard-link-missing", "", "package-1/missing.txt");
+        assert!(result.is_err());
+
+        let (_, result) = install_hard_link("hard-link-dir", "", "package-1/old");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn execute_rejects_hard_links_outside_the_archive() {
+        let (context, result) = install_hard_link("hard-link-escape", "", "../../a.txt");
+
+        assert!(result.is_err());
+        assert!(!context
+            .install_path
+            .clone()
+            .unwrap()
+            .join("link.txt")
+            .exists());
+        assert_cleaned_up(&context);
+    }
+}
@@ -3,11 +3,13 @@
 
 use std::fmt;
 use std::fmt::Display;
+use std::path::PathBuf;
 
 use installer::InstallerFramework;
+use installer::LocalInstallation;
 
 use sources::types::File;
-use sources::types::Version;
+use sources::types::Release;
 
 pub mod download_pkg;
 pub mod ensure_only_instance;
@@ -16,7 +18,9 @@ pub mod install_dir;
 pub mod install_global_shortcut;
 pub mod install_pkg;
 pub mod install_shortcuts;
+pub mod prefetch;
 pub mod resolver;
+pub mod run_hook;
 pub mod save_database;
 pub mod save_executable;
 pub mod uninstall;
@@ -27,12 +31,16 @@ pub mod uninstall_shortcuts;
 /// An abstraction over the various parameters that can be passed around.
 pub enum TaskParamType {
     None,
-    /// Metadata about a file
-    File(Version, File),
-    /// Downloaded contents of a file
-    FileContents(Version, File, Vec<u8>),
+    /// Metadata about a file, the release it belongs to, and an optional delta which
+    /// can be applied to the installed version instead
+    File(Release, File, Option<File>),
+    /// A file which has been downloaded to the specified path, and (if it was built from
+    /// a delta) the installed files which were carried over unchanged
+    DownloadedFile(Release, File, PathBuf, Vec<String>),
     /// List of shortcuts that have been generated
     GeneratedShortcuts(Vec<String>),
+    /// Metadata of a package that has been uninstalled
+    Uninstalled(LocalInstallation),
     /// Tells the runtime to break parsing other dependencies
     Break,
 }
@@ -0,0 +1,159 @@
+//! Resolves and downloads several packages at once, ahead of them being installed.
+
+use installer::InstallerFramework;
+
+use tasks::download_pkg::DownloadPackageTask;
+use tasks::DependencyTree;
+use tasks::Task;
+use tasks::TaskDependency;
+use tasks::TaskMessage;
+use tasks::TaskParamType;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use logging::LoggingErrors;
+
+/// Updates sent from download workers back to the task.
+enum WorkerMessage {
+    /// Progress of the package at the given index
+    Progress(usize, String, f64),
+    /// The download of the package at the given index has finished
+    Done(usize, Result<TaskParamType, String>, HashMap<String, f64>),
+}
+
+pub struct PrefetchPackagesTask {
+    pub items: Vec<String>,
+}
+
+impl Task for PrefetchPackagesTask {
+    fn execute(
+        &mut self,
+        input: Vec<TaskParamType>,
+        context: &mut InstallerFramework,
+        messenger: &Fn(&TaskMessage),
+    ) -> Result<TaskParamType, String> {
+        assert_eq!(input.len(), 0);
+
+        if self.items.is_empty() {
+            return Ok(TaskParamType::None);
+        }
+
+        let limit = context
+            .config
+            .as_ref()
+            .log_expect("Should have packages by now")
+            .max_parallel_downloads
+            .max(1)
+            .min(self.items.len());
+
+        info!("Downloading {} package(s), {} at a time", self.items.len(), limit);
+
+        // Reversed, as workers take packages from the end of the queue
+        let queue = Arc::new(Mutex::new(
+            self.items.iter().cloned().enumerate().rev().collect::<Vec<_>>(),
+        ));
+        let failed = Arc::new(AtomicBool::new(false));
+
+        let (sender, receiver) = channel();
+
+        for _ in 0..limit {
+            let queue = queue.clone();
+            let failed = failed.clone();
+            let sender = sender.clone();
+            let mut worker_context = context.fork();
+
+            thread::spawn(move || loop {
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let (index, name) = match queue
+                    .lock()
+                    .log_expect("Download queue has been poisoned")
+                    .pop()
+                {
+                    Some(v) => v,
+                    None => break,
+                };
+
+                let mut tree = DependencyTree::build(Box::new(DownloadPackageTask {
+                    name: name.clone(),
+                }));
+
+                let result = tree.execute(&mut worker_context, &|msg: &TaskMessage| {
+                    if let TaskMessage::DisplayMessage(msg, progress) = *msg {
+                        let message = WorkerMessage::Progress(index, msg.to_string(), progress);
+                        if let Err(v) = sender.send(message) {
+                            error!("Failed to submit download progress: {:?}", v);
+                        }
+                    }
+                });
+
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+
+                let speeds = worker_context.database.mirror_speeds.clone();
+                if let Err(v) = sender.send(WorkerMessage::Done(index, result, speeds)) {
+                    error!("Failed to submit download result: {:?}", v);
+                }
+            });
+        }
+
+        // Workers each hold their own sender, so this ends once they have all finished
+        drop(sender);
+
+        let known_speeds = context.database.mirror_speeds.clone();
+
+        let mut progress = vec![0.0; self.items.len()];
+        let mut error = None;
+
+        for message in receiver {
+            match message {
+                WorkerMessage::Progress(index, msg, value) => {
+                    progress[index] = value;
+
+                    let overall = progress.iter().sum::<f64>() / progress.len() as f64;
+                    messenger(&TaskMessage::DisplayMessage(&msg, overall));
+                }
+                WorkerMessage::Done(index, result, speeds) => {
+                    progress[index] = 1.0;
+
+                    // Only take speeds which this worker actually measured
+                    for (origin, speed) in speeds {
+                        if known_speeds.get(&origin) != Some(&speed) {
+                            context.database.mirror_speeds.insert(origin, speed);
+                        }
+                    }
+
+                    match result {
+                        Ok(v) => {
+                            context.prefetched.insert(self.items[index].clone(), v);
+                        }
+                        Err(v) => {
+                            error!("Failed to download {:?}: {}", self.items[index], v);
+                            error = error.or(Some(v));
+                        }
+                    }
+                }
+            }
+        }
+
+        match error {
+            Some(v) => Err(v),
+            None => Ok(TaskParamType::None),
+        }
+    }
+
+    fn dependencies(&self) -> Vec<TaskDependency> {
+        vec![]
+    }
+
+    fn name(&self) -> String {
+        format!("PrefetchPackagesTask (for {:?})", self.items)
+    }
+}
@@ -11,14 +11,207 @@ use tasks::TaskParamType;
 
 use config::PackageDescription;
 
-use regex::Regex;
+use regex::{escape, Regex};
 
 use logging::LoggingErrors;
 
+use sources::types::File;
+use sources::types::Release;
+
+use checksum::find_in_sums;
+
+use http::download_text;
+
 pub struct ResolvePackageTask {
     pub name: String,
 }
 
+/// Fetches all releases of a package which could be installed, newest first, alongside
+/// the file in each that matches this platform. This takes into account the channel
+/// being followed, the package's configured pin and an optional additional requirement.
+pub fn list_releases(
+    context: &InstallerFramework,
+    package: &PackageDescription,
+    requirement: Option<&str>,
+) -> Result<Vec<(Release, File)>, String> {
+    let cache_dir = context.get_cache_dir();
+    let results = package
+        .source
+        .get_current_releases(cache_dir.as_ref().map(|x| x.as_path()))?;
+
+    // Work out which channel to follow: an explicit choice, then what is already
+    // installed, then the package's default
+    let requested_channel = context
+        .selections
+        .get(&package.name)
+        .and_then(|x| x.channel.clone())
+        .or_else(|| {
+            context
+                .database
+                .packages
+                .iter()
+                .find(|x| x.name == package.name)
+                .and_then(|x| x.channel.clone())
+        });
+
+    let channel = match requested_channel {
+        Some(name) => match package.channels.iter().find(|x| x.name == name) {
+            Some(v) => Some(v),
+            None => {
+                warn!(
+                    "Channel {:?} no longer exists for {:?}, using the default",
+                    name, package.name
+                );
+                package.channels.first()
+            }
+        },
+        None => package.channels.first(),
+    };
+
+    if let Some(channel) = channel {
+        info!("Following channel {:?} for {:?}", channel.name, package.name);
+    }
+
+    let filtered_regex = package.source.match_regex.replace("#PLATFORM#", OS);
+    let regex = match Regex::new(&filtered_regex) {
+        Ok(v) => v,
+        Err(v) => return Err(format!("An error occurred while compiling regex: {:?}", v)),
+    };
+
+    let mut releases = Vec::new();
+
+    for mut release in results {
+        if let Some(channel) = channel {
+            if !channel.matches(&release)? {
+                continue;
+            }
+
+            release.channel = Some(channel.name.clone());
+        }
+
+        if let Some(ref pin) = package.pin {
+            if !release.version.matches(pin) {
+                continue;
+            }
+        }
+
+        if let Some(requirement) = requirement {
+            if !release.version.matches(requirement) {
+                continue;
+            }
+        }
+
+        // Find the matching file in here
+        let file = match release.files.iter().find(|x| regex.is_match(&x.name)) {
+            Some(v) => v.clone(),
+            None => continue,
+        };
+
+        releases.push((release, file));
+    }
+
+    releases.sort_by(|a, b| b.0.version.cmp(&a.0.version));
+
+    Ok(releases)
+}
+
+/// Finds the expected SHA-256 digest of a file which its source didn't provide one for,
+/// either from the config or from a checksum listing published with the release.
+fn find_checksum(
+    package: &PackageDescription,
+    release: &Release,
+    file: &File,
+) -> Result<Option<String>, String> {
+    if let Some(digest) = package.source.sha256.get(&file.name) {
+        return Ok(Some(digest.clone()));
+    }
+
+    let checksums = match package.source.checksums {
+        Some(ref v) => v,
+        None => return Ok(None),
+    };
+
+    let regex = Regex::new(checksums)
+        .map_err(|x| format!("An error occurred while compiling regex: {:?}", x))?;
+
+    let listing = match release.files.iter().find(|x| regex.is_match(&x.name)) {
+        Some(v) => v,
+        None => {
+            return Err(format!(
+                "Release {} of {:?} has no checksum file",
+                release.version, package.name
+            ))
+        }
+    };
+
+    let sums = download_text(&listing.url)?;
+
+    match find_in_sums(&sums, &file.name) {
+        Some(digest) => Ok(Some(digest)),
+        None => Err(format!(
+            "{:?} doesn't list a checksum for {:?}",
+            listing.name, file.name
+        )),
+    }
+}
+
+/// Finds a delta from the installed version of a package to a release, if the source
+/// publishes them.
+fn find_delta(
+    context: &InstallerFramework,
+    package: &PackageDescription,
+    release: &Release,
+) -> Result<Option<File>, String> {
+    let pattern = match package.source.delta {
+        Some(ref v) => v,
+        None => return Ok(None),
+    };
+
+    let installed = match context
+        .database
+        .packages
+        .iter()
+        .find(|x| x.name == package.name)
+    {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if installed.version == release.version {
+        return Ok(None);
+    }
+
+    let pattern = pattern
+        .replace("#PLATFORM#", OS)
+        .replace("#FROM#", &escape(&installed.version.to_string()));
+
+    let regex = Regex::new(&pattern)
+        .map_err(|x| format!("An error occurred while compiling regex: {:?}", x))?;
+
+    let mut delta = match release.files.iter().find(|x| regex.is_match(&x.name)) {
+        Some(v) => v.clone(),
+        None => {
+            info!(
+                "No delta from {} to {} is available for {:?}",
+                installed.version, release.version, package.name
+            );
+            return Ok(None);
+        }
+    };
+
+    // Deltas are optional, so a missing checksum shouldn't stop the update
+    if delta.sha256.is_none() {
+        delta.sha256 = find_checksum(package, release, &delta).unwrap_or_else(|x| {
+            warn!("Unable to find a checksum for delta {:?}: {}", delta.name, x);
+            None
+        });
+    }
+
+    info!("Selected delta: {:?}", delta);
+
+    Ok(Some(delta))
+}
+
 impl Task for ResolvePackageTask {
     fn execute(
         &mut self,
@@ -27,6 +220,12 @@ impl Task for ResolvePackageTask {
         messenger: &Fn(&TaskMessage),
     ) -> Result<TaskParamType, String> {
         assert_eq!(input.len(), 0);
+
+        // The download has already been done, so there's nothing to resolve
+        if context.prefetched.contains_key(&self.name) {
+            return Ok(TaskParamType::None);
+        }
+
         let mut metadata: Option<PackageDescription> = None;
         for description in &context
             .config
@@ -53,42 +252,51 @@ impl Task for ResolvePackageTask {
             0.0,
         ));
 
-        let results = package.source.get_current_releases()?;
+        // Explicit choices by the user take priority over existing pins
+        let installed_pin = context
+            .database
+            .packages
+            .iter()
+            .find(|x| x.name == self.name)
+            .and_then(|x| x.pinned.clone());
+
+        let pin = match context.selections.get(&self.name) {
+            Some(selection) => selection.pin(installed_pin.as_ref().map(|x| x.as_str())),
+            None => installed_pin,
+        };
+
+        let mut releases = list_releases(context, &package, pin.as_ref().map(|x| x.as_str()))?;
 
         messenger(&TaskMessage::DisplayMessage(
             &format!("Resolving dependency for {:?}...", package.name),
             0.5,
         ));
 
-        let filtered_regex = package.source.match_regex.replace("#PLATFORM#", OS);
-        let regex = match Regex::new(&filtered_regex) {
-            Ok(v) => v,
-            Err(v) => return Err(format!("An error occurred while compiling regex: {:?}", v)),
-        };
-
-        // Find the latest release in here
-        let latest_result = results
-            .into_iter()
-            .filter(|f| f.files.iter().filter(|x| regex.is_match(&x.name)).count() > 0)
-            .max_by_key(|f| f.version.clone());
+        if releases.is_empty() {
+            return match pin {
+                Some(pin) => Err(format!(
+                    "No release of {:?} matching {:?} found",
+                    package.name, pin
+                )),
+                None => Err("No release with correct file found".to_string()),
+            };
+        }
 
-        let latest_result = match latest_result {
-            Some(v) => v,
-            None => return Err("No release with correct file found".to_string()),
-        };
+        // Releases are sorted newest first
+        let (latest_result, mut latest_file) = releases.remove(0);
 
-        let latest_version = latest_result.version.clone();
+        if latest_file.sha256.is_none() {
+            latest_file.sha256 = find_checksum(&package, &latest_result, &latest_file)?;
+        }
 
-        // Find the matching file in here
-        let latest_file = latest_result
-            .files
-            .into_iter()
-            .find(|x| regex.is_match(&x.name))
-            .log_expect("Searched file should have existed, but didn't");
+        let delta = find_delta(context, &package, &latest_result)?;
 
-        info!("Selected file: {:?}", latest_file);
+        info!(
+            "Selected file: {:?} (from release {:?})",
+            latest_file, latest_result.tag
+        );
 
-        Ok(TaskParamType::File(latest_version, latest_file))
+        Ok(TaskParamType::File(latest_result, latest_file, delta))
     }
 
     fn dependencies(&self) -> Vec<TaskDependency> {
@@ -0,0 +1,277 @@
+//! Runs a command configured by a package at a point in its lifecycle.
+
+use installer::InstallerFramework;
+
+use tasks::download_pkg::DownloadPackageTask;
+use tasks::Task;
+use tasks::TaskDependency;
+use tasks::TaskMessage;
+use tasks::TaskOrdering;
+use tasks::TaskParamType;
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use logging::LoggingErrors;
+
+/// How many lines of output are included when a hook fails.
+const FAILURE_OUTPUT_LINES: usize = 5;
+
+/// The points in a package's lifecycle where hooks can run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookKind {
+    PreInstall,
+    PostInstall,
+    PreUninstall,
+    PostUninstall,
+}
+
+impl HookKind {
+    /// The name of this hook, as used in the config.
+    fn name(&self) -> &'static str {
+        match *self {
+            HookKind::PreInstall => "pre_install",
+            HookKind::PostInstall => "post_install",
+            HookKind::PreUninstall => "pre_uninstall",
+            HookKind::PostUninstall => "post_uninstall",
+        }
+    }
+}
+
+/// Forwards each line read from a stream of a hook's output.
+fn forward_lines<R: Read + Send + 'static>(stream: R, sender: Sender<String>) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(v) => {
+                    warn!("Failed to read hook output: {:?}", v);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Runs a hook command through the platform's shell, reporting its output as it runs.
+fn run_command(
+    command: &str,
+    install_path: &Path,
+    env: &[(&str, String)],
+    messenger: &Fn(&TaskMessage),
+) -> Result<(), String> {
+    let mut process = if cfg!(windows) {
+        let mut process = Command::new("cmd");
+        process.arg("/C").arg(command);
+        process
+    } else {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+
+    process
+        .current_dir(install_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    for &(key, ref value) in env {
+        process.env(key, value);
+    }
+
+    let mut child = process
+        .spawn()
+        .map_err(|x| format!("Unable to start {:?}: {:?}", command, x))?;
+
+    let (sender, receiver) = channel();
+
+    forward_lines(
+        child.stdout.take().log_expect("Hook should have a stdout pipe"),
+        sender.clone(),
+    );
+    forward_lines(
+        child.stderr.take().log_expect("Hook should have a stderr pipe"),
+        sender,
+    );
+
+    let mut recent = Vec::new();
+
+    // Both streams hold a sender, so this ends once the hook has closed them
+    for line in receiver {
+        info!("[hook] {}", line);
+        messenger(&TaskMessage::DisplayMessage(&line, 0.5));
+
+        recent.push(line);
+        if recent.len() > FAILURE_OUTPUT_LINES {
+            recent.remove(0);
+        }
+    }
+
+    let status = child
+        .wait()
+        .map_err(|x| format!("Unable to wait for {:?}: {:?}", command, x))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed ({}):\n{}", command, status, recent.join("\n")))
+    }
+}
+
+/// Runs the hook a package has configured for a point in its lifecycle, if any. Versions
+/// are passed to the hook in its environment.
+pub fn run_hook(
+    context: &InstallerFramework,
+    name: &str,
+    kind: HookKind,
+    old_version: Option<String>,
+    new_version: Option<String>,
+    messenger: &Fn(&TaskMessage),
+) -> Result<(), String> {
+    let command = context
+        .config
+        .as_ref()
+        .log_expect("Should have packages by now")
+        .packages
+        .iter()
+        .find(|x| x.name == name)
+        .and_then(|x| match kind {
+            HookKind::PreInstall => x.hooks.pre_install.clone(),
+            HookKind::PostInstall => x.hooks.post_install.clone(),
+            HookKind::PreUninstall => x.hooks.pre_uninstall.clone(),
+            HookKind::PostUninstall => x.hooks.post_uninstall.clone(),
+        });
+
+    let command = match command {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let install_path = context
+        .install_path
+        .clone()
+        .log_expect("No install path specified");
+
+    messenger(&TaskMessage::DisplayMessage(
+        &format!("Running {} hook for package {:?}...", kind.name(), name),
+        0.0,
+    ));
+
+    info!("Running {} hook for {:?}: {:?}", kind.name(), name, command);
+
+    let env = [
+        ("LIFTINSTALL_HOOK", kind.name().to_string()),
+        ("LIFTINSTALL_PACKAGE", name.to_string()),
+        (
+            "LIFTINSTALL_INSTALL_PATH",
+            install_path.to_string_lossy().into_owned(),
+        ),
+        ("LIFTINSTALL_OLD_VERSION", old_version.unwrap_or_default()),
+        ("LIFTINSTALL_NEW_VERSION", new_version.unwrap_or_default()),
+    ];
+
+    run_command(&command, &install_path, &env, messenger).map_err(|x| {
+        format!(
+            "The {} hook of package {:?} failed: {}",
+            kind.name(),
+            name,
+            x
+        )
+    })
+}
+
+/// Runs a pre-install or pre-uninstall hook. Post hooks are run by the install and
+/// uninstall tasks themselves, so that their failure can be rolled back.
+pub struct RunHookTask {
+    pub name: String,
+    pub kind: HookKind,
+}
+
+impl Task for RunHookTask {
+    fn execute(
+        &mut self,
+        mut input: Vec<TaskParamType>,
+        context: &mut InstallerFramework,
+        messenger: &Fn(&TaskMessage),
+    ) -> Result<TaskParamType, String> {
+        // Pre-install hooks pass the download through, so they know what is installed
+        let mut output = TaskParamType::None;
+        let mut downloaded_version = None;
+
+        if self.kind == HookKind::PreInstall {
+            output = input.pop().log_expect("Should have input from downloader!");
+
+            // If the downloader returned early, we need to unwind
+            if let TaskParamType::Break = output {
+                return Ok(output);
+            }
+
+            match output {
+                TaskParamType::DownloadedFile(ref release, _, _, _) => {
+                    downloaded_version = Some(release.version.to_string());
+                }
+                _ => return Err("Unexpected param type to pre-install hook".to_string()),
+            }
+        }
+
+        let installed_version = context
+            .database
+            .packages
+            .iter()
+            .find(|x| x.name == self.name)
+            .map(|x| x.version.to_string());
+
+        let (old_version, new_version) = match self.kind {
+            HookKind::PreInstall => (installed_version, downloaded_version),
+            HookKind::PreUninstall => match installed_version {
+                Some(v) => (Some(v), None),
+                None => return Ok(output),
+            },
+            HookKind::PostInstall | HookKind::PostUninstall => {
+                return Err(format!("{} hooks can't be run as a task", self.kind.name()));
+            }
+        };
+
+        run_hook(
+            context,
+            &self.name,
+            self.kind,
+            old_version,
+            new_version,
+            messenger,
+        )?;
+
+        Ok(output)
+    }
+
+    fn dependencies(&self) -> Vec<TaskDependency> {
+        match self.kind {
+            HookKind::PreInstall => vec![TaskDependency::build(
+                TaskOrdering::Pre,
+                Box::new(DownloadPackageTask {
+                    name: self.name.clone(),
+                }),
+            )],
+            _ => vec![],
+        }
+    }
+
+    fn name(&self) -> String {
+        format!(
+            "RunHookTask (for {:?}, hook = {})",
+            self.name,
+            self.kind.name()
+        )
+    }
+}
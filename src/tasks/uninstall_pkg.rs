@@ -2,19 +2,15 @@
 
 use installer::InstallerFramework;
 
-use tasks::save_database::SaveDatabaseTask;
+use tasks::install_pkg::{back_up_files, finish_swap, recover_interrupted, roll_back};
 use tasks::Task;
 use tasks::TaskDependency;
 use tasks::TaskMessage;
 use tasks::TaskOrdering;
 use tasks::TaskParamType;
 
-use installer::LocalInstallation;
-
-use std::fs::remove_dir;
-use std::fs::remove_file;
-
 use logging::LoggingErrors;
+use tasks::run_hook::{run_hook, HookKind, RunHookTask};
 use tasks::uninstall_shortcuts::UninstallShortcutsTask;
 
 pub struct UninstallPackageTask {
@@ -29,22 +25,19 @@ impl Task for UninstallPackageTask {
         context: &mut InstallerFramework,
         messenger: &Fn(&TaskMessage),
     ) -> Result<TaskParamType, String> {
-        assert_eq!(input.len(), 1);
+        assert_eq!(input.len(), 2);
 
         let path = context
             .install_path
-            .as_ref()
+            .clone()
             .log_expect("No install path specified");
 
-        let mut metadata: Option<LocalInstallation> = None;
-        for i in 0..context.database.packages.len() {
-            if self.name == context.database.packages[i].name {
-                metadata = Some(context.database.packages.remove(i));
-                break;
-            }
-        }
-
-        let mut package = match metadata {
+        let index = match context
+            .database
+            .packages
+            .iter()
+            .position(|x| x.name == self.name)
+        {
             Some(v) => v,
             None => {
                 if self.optional {
@@ -63,36 +56,66 @@ impl Task for UninstallPackageTask {
             0.0,
         ));
 
-        // Reverse, as to delete directories last
-        package.files.reverse();
-
-        let max = package.files.len();
-        for (i, file) in package.files.iter().enumerate() {
-            let name = file.clone();
-            let file = path.join(file);
-            info!("Deleting {:?}", file);
-
-            messenger(&TaskMessage::DisplayMessage(
-                &format!("Deleting {} ({} of {})", name, i + 1, max),
-                (i as f64) / (max as f64),
-            ));
-
-            let result = if file.is_dir() {
-                remove_dir(file)
-            } else {
-                remove_file(file)
-            };
+        // Files are moved aside until the uninstall has been recorded, so that they can
+        // be restored if anything fails
+        let dir_name = self.name.replace(|c: char| c == '/' || c == '\\', "_");
+        let backup_path = context
+            .get_cache_dir()
+            .log_expect("Should have an install path by now")
+            .join("backup")
+            .join(&dir_name);
+
+        recover_interrupted(&path, &backup_path)?;
+
+        let package = context.database.packages.remove(index);
+
+        let result = back_up_files(&path, &backup_path, &package.files)
+            .and_then(|_| {
+                run_hook(
+                    context,
+                    &self.name,
+                    HookKind::PostUninstall,
+                    Some(package.version.to_string()),
+                    None,
+                    messenger,
+                )
+            })
+            .and_then(|_| context.save_database());
+
+        if let Err(v) = result {
+            error!("Failed to uninstall {:?}, rolling back: {}", self.name, v);
+
+            context.database.packages.insert(index, package);
+
+            if let Err(e) = context.save_database() {
+                warn!("Unable to restore the previous database: {}", e);
+            }
 
-            if let Err(v) = result {
-                error!("Failed to delete file: {:?}", v);
+            if let Err(e) = roll_back(&path, &backup_path, &[]) {
+                return Err(format!(
+                    "{} (restoring the previous files also failed: {})",
+                    v, e
+                ));
             }
+
+            return Err(v);
         }
 
-        Ok(TaskParamType::None)
+        // Leaves behind directories which still contain files the user created
+        finish_swap(&path, &backup_path, &package.files, &[]);
+
+        Ok(TaskParamType::Uninstalled(package))
     }
 
     fn dependencies(&self) -> Vec<TaskDependency> {
         vec![
+            TaskDependency::build(
+                TaskOrdering::Pre,
+                Box::new(RunHookTask {
+                    name: self.name.clone(),
+                    kind: HookKind::PreUninstall,
+                }),
+            ),
             TaskDependency::build(
                 TaskOrdering::Pre,
                 Box::new(UninstallShortcutsTask {
@@ -100,7 +123,6 @@ impl Task for UninstallPackageTask {
                     optional: self.optional,
                 }),
             ),
-            TaskDependency::build(TaskOrdering::Post, Box::new(SaveDatabaseTask {})),
         ]
     }
 
@@ -133,6 +133,19 @@ function stream_ajax(path, callback, successCallback, failCallback, data) {
     }
 }
 
+/**
+ * Returns the name of the channel a package follows if none has been chosen yet.
+ *
+ * @param package_config The package, as described by the config.
+ */
+function default_channel(package_config) {
+    if (package_config.channels == null || package_config.channels.length === 0) {
+        return null;
+    }
+
+    return package_config.channels[0].name;
+}
+
 /**
  * The default handler if a AJAX request fails. Not to be used directly.
  *
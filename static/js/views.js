@@ -57,14 +57,24 @@ const DownloadConfig = {
                 for (var x = 0; x < app.config.packages.length; x++) {
                     app.config.packages[x].default = false;
                     app.config.packages[x].installed = false;
+                    app.config.packages[x].installed_version = null;
+                    app.config.packages[x].channel = default_channel(app.config.packages[x]);
                 }
 
                 for (var i = 0; i < app.metadata.database.packages.length; i++) {
+                    var installed_package = app.metadata.database.packages[i];
+
                     // Find this config package
                     for (var x = 0; x < app.config.packages.length; x++) {
-                        if (app.config.packages[x].name === app.metadata.database.packages[i].name) {
+                        if (app.config.packages[x].name === installed_package.name) {
                             app.config.packages[x].default = true;
                             app.config.packages[x].installed = true;
+                            app.config.packages[x].installed_version = installed_package.release_name
+                                || installed_package.tag;
+
+                            if (installed_package.channel != null) {
+                                app.config.packages[x].channel = installed_package.channel;
+                            }
                         }
                     }
                 }
@@ -77,6 +87,7 @@ const DownloadConfig = {
             } else {
                 for (var x = 0; x < app.config.packages.length; x++) {
                     app.config.packages[x].installed = false;
+                    app.config.packages[x].channel = default_channel(app.config.packages[x]);
                 }
 
                 // Need to do a bit more digging to get at the
@@ -106,12 +117,20 @@ const SelectPackages = {
                             <label class="checkbox">
                                 <input type="checkbox" v-model="package.default" />
                                 {{ package.name }}
-                                <span v-if="package.installed"><i>(installed)</i></span>
+                                <span v-if="package.installed && package.installed_version"><i>(installed: {{ package.installed_version }})</i></span>
+                                <span v-else-if="package.installed"><i>(installed)</i></span>
                             </label>
                             <p>
                                 {{ package.description }}
                             </p>
                         </div>
+                        <div class="select is-small" v-if="package.channels.length > 0">
+                            <select v-model="package.channel">
+                                <option v-for="channel in package.channels" :value="channel.name">
+                                    {{ channel.description || channel.name }}
+                                </option>
+                            </select>
+                        </div>
                     </div>
                 </div>
             </div>
@@ -152,11 +171,43 @@ const SelectPackages = {
                        v-on:click="go_back">Back</a>
                 </p>
             </div>
+
+            <div class="modal is-active" v-if="plan != null">
+                <div class="modal-background"></div>
+                <div class="modal-card">
+                    <header class="modal-card-head">
+                        <p class="modal-card-title" v-if="plan.errors.length > 0">These packages can't be installed together</p>
+                        <p class="modal-card-title" v-else>The following changes will be made</p>
+                    </header>
+                    <section class="modal-card-body">
+                        <div class="content">
+                            <ul v-if="plan.errors.length > 0">
+                                <li v-for="error in plan.errors">{{ error }}</li>
+                            </ul>
+                            <div v-else>
+                                <p v-if="plan.install.length > 0"><b>Install or update:</b></p>
+                                <ul>
+                                    <li v-for="package in plan.install">{{ package.name }} <i>({{ package.reason }})</i></li>
+                                </ul>
+                                <p v-if="plan.uninstall.length > 0"><b>Uninstall:</b></p>
+                                <ul>
+                                    <li v-for="package in plan.uninstall">{{ package.name }} <i>({{ package.reason }})</i></li>
+                                </ul>
+                            </div>
+                        </div>
+                    </section>
+                    <footer class="modal-card-foot">
+                        <button class="button is-dark" v-if="plan.errors.length === 0" v-on:click="confirm_install">Continue</button>
+                        <button class="button" v-on:click="plan = null">Back</button>
+                    </footer>
+                </div>
+            </div>
         </div>
     `,
     data: function() {
         return {
-            advanced: false
+            advanced: false,
+            plan: null
         }
     },
     methods: {
@@ -168,6 +219,32 @@ const SelectPackages = {
             }));
         },
         install: function() {
+            var results = {};
+
+            for (var package_index = 0; package_index < app.config.packages.length; package_index++) {
+                var current_package = app.config.packages[package_index];
+                results[current_package.name] = current_package.default === true;
+            }
+
+            var that = this; // IE workaround
+
+            ajax("/api/plan", function(e) {
+                that.plan = e;
+            }, undefined, results);
+        },
+        confirm_install: function() {
+            // Select packages which were pulled in by others
+            for (var package_index = 0; package_index < app.config.packages.length; package_index++) {
+                var current_package = app.config.packages[package_index];
+
+                for (var i = 0; i < this.plan.install.length; i++) {
+                    if (this.plan.install[i].name === current_package.name) {
+                        current_package.default = true;
+                    }
+                }
+            }
+
+            this.plan = null;
             router.push("/install/regular");
         },
         go_back: function() {
@@ -219,6 +296,13 @@ const InstallPackages = {
                 if (current_package.default != null) {
                     results[current_package.name] = current_package.default;
                 }
+                if (current_package.channel != null) {
+                    results["channel:" + current_package.name] = current_package.channel;
+                }
+                if (current_package.version != null) {
+                    results["version:" + current_package.name] = current_package.version;
+                    current_package.version = null;
+                }
             }
 
             results["path"] = app.install_location;
@@ -375,7 +459,22 @@ const ModifyView = {
             </a>
             <br />
             <br />
+
+            <a class="button is-dark is-medium" v-on:click="change_versions">
+                Change Version
+            </a>
+            <br />
+            <br />
             
+            <a class="button is-dark is-medium" v-on:click="clear_cache" :disabled="cache == null || cache.files === 0">
+                Clear Download Cache
+            </a>
+            <span v-if="cache != null">
+                <i>({{ cache.files }} file(s), {{ format_size(cache.size) }} of {{ format_size(cache.max_size) }})</i>
+            </span>
+            <br />
+            <br />
+
             <a class="button is-dark is-medium" v-on:click="prepare_uninstall">
                 Uninstall
             </a>
@@ -396,16 +495,37 @@ const ModifyView = {
     `,
     data: function() {
         return {
-            show_uninstall: false
+            show_uninstall: false,
+            cache: null
         }
     },
+    created: function() {
+        var that = this; // IE workaround
+
+        ajax("/api/cache", function(e) {
+            that.cache = e;
+        });
+    },
     methods: {
         update: function() {
             router.push("/install/update");
         },
+        format_size: function(bytes) {
+            return (bytes / (1024 * 1024)).toFixed(1) + " MB";
+        },
+        clear_cache: function() {
+            var that = this; // IE workaround
+
+            ajax("/api/clear-cache", function(e) {
+                that.cache = e;
+            }, undefined, {});
+        },
         modify_packages: function() {
             router.push("/packages");
         },
+        change_versions: function() {
+            router.push("/versions");
+        },
         prepare_uninstall: function() {
             this.show_uninstall = true;
         },
@@ -418,6 +538,109 @@ const ModifyView = {
     }
 };
 
+const VersionsView = {
+    template: `
+        <div class="column has-padding">
+            <h4 class="subtitle">Choose a version to install:</h4>
+
+            <div class="field">
+                <div class="select">
+                    <select v-model="package_name" v-on:change="load_releases">
+                        <option v-for="package in $root.$data.metadata.database.packages" :value="package.name">
+                            {{ package.name }}
+                        </option>
+                    </select>
+                </div>
+                <span v-if="pinned"><i>(pinned to {{ pinned }})</i></span>
+            </div>
+
+            <p v-if="loading">Loading releases...</p>
+            <div class="versions-list" v-else>
+                <div class="box clickable-box" v-on:click="install('latest')">
+                    <b>Latest</b> - always keep this package up to date
+                </div>
+                <div class="box clickable-box" v-for="release in releases" :index="release.version"
+                     v-on:click="install(release.version)">
+                    <b>{{ release.name || release.tag || release.version }}</b>
+                    <span v-if="release.prerelease"><i>(prerelease)</i></span>
+                    <span v-if="release.version === installed_version"><i>(installed)</i></span>
+                </div>
+            </div>
+
+            <div class="field is-grouped is-left-floating is-bottom-floating">
+                <p class="control">
+                    <a class="button is-medium" v-on:click="go_back">Back</a>
+                </p>
+            </div>
+        </div>
+    `,
+    data: function() {
+        var packages = app.metadata.database.packages;
+
+        return {
+            package_name: packages.length > 0 ? packages[0].name : null,
+            releases: [],
+            loading: false
+        }
+    },
+    computed: {
+        installed: function() {
+            var packages = this.$root.$data.metadata.database.packages;
+            for (var i = 0; i < packages.length; i++) {
+                if (packages[i].name === this.package_name) {
+                    return packages[i];
+                }
+            }
+            return null;
+        },
+        installed_version: function() {
+            if (this.installed == null) {
+                return null;
+            }
+
+            var version = this.installed.version;
+            return version.Semver !== undefined ? version.Semver : String(version.Integer);
+        },
+        pinned: function() {
+            return this.installed != null ? this.installed.pinned : null;
+        }
+    },
+    created: function() {
+        this.load_releases();
+    },
+    methods: {
+        load_releases: function() {
+            if (this.package_name == null) {
+                return;
+            }
+
+            var that = this; // IE workaround
+            this.loading = true;
+
+            ajax("/api/releases", function(e) {
+                that.releases = e;
+                that.loading = false;
+            }, function(e) {
+                router.replace({name: 'showerr', params: {msg: "Failed to list releases: " + e}});
+            }, {
+                "package": this.package_name
+            });
+        },
+        install: function(version) {
+            for (var x = 0; x < app.config.packages.length; x++) {
+                if (app.config.packages[x].name === this.package_name) {
+                    app.config.packages[x].version = version;
+                }
+            }
+
+            router.push("/install/regular");
+        },
+        go_back: function() {
+            router.go(-1);
+        }
+    }
+};
+
 const router = new VueRouter({
     routes: [
         {
@@ -450,6 +673,11 @@ const router = new VueRouter({
             name: 'modify',
             component: ModifyView
         },
+        {
+            path: '/versions',
+            name: 'versions',
+            component: VersionsView
+        },
         {
             path: '/',
             redirect: '/config'